@@ -8,3 +8,101 @@ kernel_main:
 .fin:
   hlt
   jmp .fin
+
+; Application Processor startup code.
+; Copied to ap_trampoline_base and started by a Startup IPI in real mode.
+; The BSP fills ap_cr3, ap_stack and ap_entry in the copy before sending the IPI.
+; The base must be page aligned and below 1 MiB since APs start at vector * 0x1000.
+; It is exported as an absolute symbol, the only definition smp.rs and frame.rs use.
+global ap_trampoline_base
+ap_trampoline_base equ 0x8000
+%define TRAMPOLINE_ADDR(label) (ap_trampoline_base + (label) - ap_trampoline)
+
+global ap_trampoline
+global ap_trampoline_end
+global ap_cr3
+global ap_stack
+global ap_entry
+
+bits 16
+ap_trampoline:
+  cli
+  cld
+  xor ax, ax
+  mov ds, ax
+  lgdt [TRAMPOLINE_ADDR(ap_gdt_ptr)]
+  mov eax, cr0
+  or eax, 1 ; PE
+  mov cr0, eax
+  jmp dword 0x08:TRAMPOLINE_ADDR(ap_protected_mode)
+
+bits 32
+ap_protected_mode:
+  mov ax, 0x10
+  mov ds, ax
+  mov es, ax
+  mov ss, ax
+  mov eax, cr4
+  or eax, 1 << 5 ; PAE
+  mov cr4, eax
+  mov eax, [TRAMPOLINE_ADDR(ap_cr3)]
+  mov cr3, eax
+  mov ecx, 0xc0000080 ; IA32_EFER
+  rdmsr
+  or eax, 1 << 8 ; LME
+  wrmsr
+  mov eax, cr0
+  or eax, 1 << 31 ; PG
+  mov cr0, eax
+  jmp 0x18:TRAMPOLINE_ADDR(ap_long_mode)
+
+bits 64
+ap_long_mode:
+  mov rsp, [TRAMPOLINE_ADDR(ap_stack)]
+  mov rax, [TRAMPOLINE_ADDR(ap_entry)]
+  call rax
+.fin:
+  hlt
+  jmp .fin
+
+align 8
+ap_gdt:
+  dq 0
+  dq 0x00cf9a000000ffff ; 0x08: 32-bit code
+  dq 0x00cf92000000ffff ; 0x10: data
+  dq 0x00af9a000000ffff ; 0x18: 64-bit code
+ap_gdt_ptr:
+  dw ap_gdt_ptr - ap_gdt - 1
+  dd TRAMPOLINE_ADDR(ap_gdt)
+
+align 8
+ap_cr3:
+  dq 0
+ap_stack:
+  dq 0
+ap_entry:
+  dq 0
+ap_trampoline_end:
+
+; void switch_context(uint64_t *current_rsp, uint64_t next_rsp)
+; Save callee-saved registers and RFLAGS on the current stack, store the stack pointer
+; to *current_rsp and resume the task whose stack was saved as next_rsp.
+global switch_context
+switch_context:
+  push rbp
+  push rbx
+  push r12
+  push r13
+  push r14
+  push r15
+  pushfq
+  mov [rdi], rsp
+  mov rsp, rsi
+  popfq
+  pop r15
+  pop r14
+  pop r13
+  pop r12
+  pop rbx
+  pop rbp
+  ret
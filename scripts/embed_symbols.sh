@@ -0,0 +1,31 @@
+#!/bin/sh
+# Embed the kernel symbol table into its .ksymtab section so that panics and exceptions
+# print symbolized backtraces. `cargo run` calls it as the runner, so that builds the
+# kernel and embeds the symbols. After a plain `cargo build` run it by hand:
+#   ./scripts/embed_symbols.sh target/x86_64-rusmikan/debug/kernel.elf
+set -eu
+
+KERNEL_ELF=target/x86_64-rusmikan/debug/kernel.elf
+ELF=${1:-$KERNEL_ELF}
+# x86_64-rusmikan.json has the linker write kernel.elf, whatever output path cargo passes
+[ -f "$ELF" ] || ELF=$KERNEL_ELF
+SYMTAB=$(mktemp)
+trap 'rm -f "$SYMTAB"' EXIT
+
+SIZE=$(objdump -h "$ELF" | awk '$2 == ".ksymtab" { print $3 }')
+if [ -z "$SIZE" ]; then
+    echo "$ELF has no .ksymtab section" >&2
+    exit 1
+fi
+SIZE=$((0x$SIZE))
+
+# "<address> <demangled name>" for every function, sorted by address
+nm -n -C --defined-only "$ELF" | sed -n 's/^\([0-9a-f]*\) [tTwW] \(.*\)$/\1 \2/p' > "$SYMTAB"
+
+if [ "$(wc -c < "$SYMTAB")" -ge "$SIZE" ]; then
+    echo "symbol table does not fit in .ksymtab ($SIZE bytes)" >&2
+    exit 1
+fi
+# pad with NUL so the section keeps its size and nothing else moves
+truncate -s "$SIZE" "$SYMTAB"
+objcopy --update-section .ksymtab="$SYMTAB" "$ELF"
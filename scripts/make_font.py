@@ -0,0 +1,352 @@
+#!/usr/bin/env python3
+# Build fonts/rusmikan.psf and fonts/rusmikan-wide.psf, the PSF2 console fonts
+# embedded in the kernel.
+#
+# Glyphs come from the 8x16 table in src/ascii_font.rs (ASCII and JIS X 0201
+# half-width katakana), plus Latin-1 letters composed from their base letter
+# and an accent, box-drawing and block elements drawn here.
+#
+# The 16x16 wide font has the full-width glyphs, drawn across two console cells:
+# full-width ASCII and signs, full-width katakana stretched from the half-width
+# forms (voiced ones get their mark added), and ideographic punctuation.
+# Hiragana and kanji can't be made from the narrow glyphs and are missing.
+#
+# usage: scripts/make_font.py [src/ascii_font.rs] [fonts/rusmikan.psf] [fonts/rusmikan-wide.psf]
+
+import re
+import struct
+import sys
+import unicodedata
+
+WIDTH = 8
+HEIGHT = 16
+
+PSF2_MAGIC = 0x864AB572
+PSF2_HAS_UNICODE_TABLE = 0x01
+PSF2_SEPARATOR = 0xFF
+
+
+def load_builtin(path):
+    with open(path) as f:
+        bits = [int(b, 2) for b in re.findall(r"0b([01]{8})", f.read())]
+    return [bits[i * HEIGHT:(i + 1) * HEIGHT] for i in range(len(bits) // HEIGHT)]
+
+
+def parse(rows):
+    # rows of '#' and '.', left aligned
+    return [sum(0x80 >> i for i, c in enumerate(row) if c == "#") for row in rows]
+
+
+def overlay(glyph, rows, top):
+    glyph = list(glyph)
+    for i, row in enumerate(parse(rows)):
+        glyph[top + i] |= row
+    return glyph
+
+
+def shift_down(glyph, n):
+    return [0] * n + glyph[:HEIGHT - n]
+
+
+ACCENTS = {
+    "grave": ["..#.....", "...#...."],
+    "acute": ["....#...", "...#...."],
+    "circumflex": ["...#....", "..#.#..."],
+    "tilde": ["..##.#..", ".#.##..."],
+    "diaeresis": [".#...#.."],
+    "ring": ["...#....", "..#.#...", "...#...."],
+}
+CEDILLA = ["...#....", "..##...."]
+
+# letter, accent, code point of the lowercase form (uppercase is 0x20 less)
+LATIN1_LETTERS = [
+    ("a", "grave", 0xE0), ("a", "acute", 0xE1), ("a", "circumflex", 0xE2),
+    ("a", "tilde", 0xE3), ("a", "diaeresis", 0xE4), ("a", "ring", 0xE5),
+    ("e", "grave", 0xE8), ("e", "acute", 0xE9), ("e", "circumflex", 0xEA),
+    ("e", "diaeresis", 0xEB),
+    ("i", "grave", 0xEC), ("i", "acute", 0xED), ("i", "circumflex", 0xEE),
+    ("i", "diaeresis", 0xEF),
+    ("n", "tilde", 0xF1),
+    ("o", "grave", 0xF2), ("o", "acute", 0xF3), ("o", "circumflex", 0xF4),
+    ("o", "tilde", 0xF5), ("o", "diaeresis", 0xF6),
+    ("u", "grave", 0xF9), ("u", "acute", 0xFA), ("u", "circumflex", 0xFB),
+    ("u", "diaeresis", 0xFC),
+    ("y", "acute", 0xFD),
+]
+
+DRAWN = {
+    0xA1: ["........", "........", "...#....", "...#....", "........", "........",
+           "...#....", "...#....", "...#....", "...#....", "...#....", "...#....",
+           "...#....", "...#....", "........", "........"],
+    0xA2: ["........", "........", "........", "...#....", "..####..", ".#.#..#.",
+           ".#.#....", ".#.#....", ".#.#....", ".#.#..#.", "..####..", "...#....",
+           "........", "........", "........", "........"],
+    0xA3: ["........", "...##...", "..#..#..", "..#.....", "..#.....", "..#.....",
+           "#####...", "..#.....", "..#.....", "..#.....", "..#.....", ".##...#.",
+           "#.#####.", "........", "........", "........"],
+    0xA5: ["........", "#.....#.", ".#...#..", "..#.#...", "...#....", ".#####..",
+           "...#....", ".#####..", "...#....", "...#....", "...#....", "...#....",
+           "...#....", "........", "........", "........"],
+    0xA7: ["........", "..###...", ".#...#..", ".#......", "..##....", ".#..#...",
+           ".#...#..", "..#..#..", "...##...", ".....#..", ".#...#..", "..###...",
+           "........", "........", "........", "........"],
+    0xA9: ["........", "........", "..####..", ".#....#.", "#..##..#", "#.#....#",
+           "#.#....#", "#.#....#", "#..##..#", ".#....#.", "..####..", "........",
+           "........", "........", "........", "........"],
+    0xAB: ["........", "........", "........", "........", "........", "...#..#.",
+           "..#..#..", ".#..#...", "#..#....", ".#..#...", "..#..#..", "...#..#.",
+           "........", "........", "........", "........"],
+    0xAC: ["........", "........", "........", "........", "........", "........",
+           "#######.", "......#.", "......#.", "......#.", "........", "........",
+           "........", "........", "........", "........"],
+    0xB0: ["........", "..##....", ".#..#...", ".#..#...", "..##....", "........",
+           "........", "........", "........", "........", "........", "........",
+           "........", "........", "........", "........"],
+    0xB1: ["........", "........", "........", "...#....", "...#....", "...#....",
+           "#######.", "...#....", "...#....", "...#....", "........", "#######.",
+           "........", "........", "........", "........"],
+    0xB2: ["........", ".##.....", "#..#....", "...#....", "..#.....", ".#......",
+           "####....", "........", "........", "........", "........", "........",
+           "........", "........", "........", "........"],
+    0xB3: ["........", "###.....", "...#....", ".##.....", "...#....", "...#....",
+           "###.....", "........", "........", "........", "........", "........",
+           "........", "........", "........", "........"],
+    0xB5: ["........", "........", "........", "........", "........", "#.....#.",
+           "#.....#.", "#.....#.", "#.....#.", "#.....#.", "#....##.", "##.##.#.",
+           "#.##..#.", "#.......", "#.......", "#......."],
+    0xB7: ["........", "........", "........", "........", "........", "........",
+           "........", "...##...", "...##...", "........", "........", "........",
+           "........", "........", "........", "........"],
+    0xBB: ["........", "........", "........", "........", "........", "#..#....",
+           ".#..#...", "..#..#..", "...#..#.", "..#..#..", ".#..#...", "#..#....",
+           "........", "........", "........", "........"],
+    0xBF: ["........", "........", "...#....", "...#....", "........", "........",
+           "...#....", "...#....", "...#....", "..#.....", ".#......", "#.....#.",
+           ".#...#..", "..###...", "........", "........"],
+    0xC6: ["........", "..#####.", ".#.#....", ".#.#....", "#..#....", "#..#....",
+           "#..####.", "#####...", "#..#....", "#..#....", "#..#....", "#..#....",
+           "#..#....", "#..#####", "........", "........"],
+    0xD7: ["........", "........", "........", "........", "#.....#.", ".#...#..",
+           "..#.#...", "...#....", "..#.#...", ".#...#..", "#.....#.", "........",
+           "........", "........", "........", "........"],
+    0xDF: ["........", "..###...", ".#...#..", ".#...#..", ".#...#..", ".#..#...",
+           ".#.#....", ".#..#...", ".#...#..", ".#....#.", ".#....#.", ".#....#.",
+           ".#.#.#..", ".#..#...", "........", "........"],
+    0xE6: ["........", "........", "........", "........", "........", ".##.##..",
+           "#..#..#.", "...#..#.", ".######.", "#..#....", "#..#....", "#..#..#.",
+           "#..#..#.", ".##.##..", "........", "........"],
+    0xF7: ["........", "........", "........", "........", "...#....", "...#....",
+           "........", "#######.", "........", "...#....", "...#....", "........",
+           "........", "........", "........", "........"],
+}
+
+REPLACEMENT = ["........", "########", "###..###", "##.##.##", "#####.##", "####.###",
+               "###.####", "###.####", "########", "###.####", "###.####", "########",
+               "########", "........", "........", "........"]
+
+# box drawing: (left, right, up, down) with 1 light, 2 heavy, 3 double
+BOX = {
+    0x2500: (1, 1, 0, 0), 0x2501: (2, 2, 0, 0), 0x2502: (0, 0, 1, 1), 0x2503: (0, 0, 2, 2),
+    0x250C: (0, 1, 0, 1), 0x250F: (0, 2, 0, 2), 0x2510: (1, 0, 0, 1), 0x2513: (2, 0, 0, 2),
+    0x2514: (0, 1, 1, 0), 0x2517: (0, 2, 2, 0), 0x2518: (1, 0, 1, 0), 0x251B: (2, 0, 2, 0),
+    0x251C: (0, 1, 1, 1), 0x2523: (0, 2, 2, 2), 0x2524: (1, 0, 1, 1), 0x252B: (2, 0, 2, 2),
+    0x252C: (1, 1, 0, 1), 0x2533: (2, 2, 0, 2), 0x2534: (1, 1, 1, 0), 0x253B: (2, 2, 2, 0),
+    0x253C: (1, 1, 1, 1), 0x254B: (2, 2, 2, 2),
+    0x2550: (3, 3, 0, 0), 0x2551: (0, 0, 3, 3),
+    0x2552: (0, 3, 0, 1), 0x2553: (0, 1, 0, 3), 0x2554: (0, 3, 0, 3),
+    0x2555: (3, 0, 0, 1), 0x2556: (1, 0, 0, 3), 0x2557: (3, 0, 0, 3),
+    0x2558: (0, 3, 1, 0), 0x2559: (0, 1, 3, 0), 0x255A: (0, 3, 3, 0),
+    0x255B: (3, 0, 1, 0), 0x255C: (1, 0, 3, 0), 0x255D: (3, 0, 3, 0),
+    0x255E: (0, 3, 1, 1), 0x255F: (0, 1, 3, 3), 0x2560: (0, 3, 3, 3),
+    0x2561: (3, 0, 1, 1), 0x2562: (1, 0, 3, 3), 0x2563: (3, 0, 3, 3),
+    0x2564: (3, 3, 0, 1), 0x2565: (1, 1, 0, 3), 0x2566: (3, 3, 0, 3),
+    0x2567: (3, 3, 1, 0), 0x2568: (1, 1, 3, 0), 0x2569: (3, 3, 3, 0),
+    0x256A: (3, 3, 1, 1), 0x256B: (1, 1, 3, 3), 0x256C: (3, 3, 3, 3),
+    0x256D: (0, 1, 0, 1), 0x256E: (1, 0, 0, 1), 0x256F: (1, 0, 1, 0), 0x2570: (0, 1, 1, 0),
+    0x2574: (1, 0, 0, 0), 0x2575: (0, 0, 1, 0), 0x2576: (0, 1, 0, 0), 0x2577: (0, 0, 0, 1),
+}
+
+# columns of vertical strokes and rows of horizontal strokes for each weight
+VERTICAL = {1: [3], 2: [3, 4], 3: [2, 5]}
+HORIZONTAL = {1: [7], 2: [7, 8], 3: [6, 9]}
+
+
+def box(left, right, up, down):
+    pixels = [[False] * WIDTH for _ in range(HEIGHT)]
+    # arms reach into the centre block so that corners and junctions connect
+    cx0, cx1 = 2, 5
+    cy0, cy1 = 6, 9
+    for weight, xs in ((left, range(0, cx1 + 1)), (right, range(cx0, WIDTH))):
+        for y in HORIZONTAL.get(weight, []):
+            for x in xs:
+                pixels[y][x] = True
+    for weight, ys in ((up, range(0, cy1 + 1)), (down, range(cy0, HEIGHT))):
+        for x in VERTICAL.get(weight, []):
+            for y in ys:
+                pixels[y][x] = True
+    return [sum(0x80 >> x for x in range(WIDTH) if pixels[y][x]) for y in range(HEIGHT)]
+
+
+def blocks():
+    full = 0xFF
+    out = {
+        0x2580: [full] * 8 + [0] * 8,
+        0x2588: [full] * HEIGHT,
+        0x258C: [0xF0] * HEIGHT,
+        0x2590: [0x0F] * HEIGHT,
+        0x2591: [0x88 if y % 2 else 0x22 for y in range(HEIGHT)],
+        0x2592: [0xAA if y % 2 else 0x55 for y in range(HEIGHT)],
+        0x2593: [0x77 if y % 2 else 0xDD for y in range(HEIGHT)],
+        0x25A0: [0] * 4 + [0x7C] * 7 + [0] * 5,
+    }
+    # lower one eighth to seven eighths, then full
+    for i in range(1, 8):
+        out[0x2580 + i] = [0] * (HEIGHT - 2 * i) + [full] * (2 * i)
+    # left seven eighths down to one eighth
+    for i in range(7, 0, -1):
+        out[0x2590 - i] = [(0xFF << (8 - i)) & 0xFF] * HEIGHT
+    return out
+
+
+def build(builtin):
+    glyphs = []  # (bitmap, [code points])
+
+    glyphs.append((parse(REPLACEMENT), [0xFFFD]))
+
+    # ASCII, U+00A0 shares the space
+    for c in range(0x20, 0x7F):
+        extra = {0x20: [0xA0], 0x2D: [0xAD, 0x2010, 0x2011, 0x2212], 0x27: [0x2018, 0x2019],
+                 0x22: [0x201C, 0x201D]}.get(c, [])
+        glyphs.append((builtin[c], [c] + extra))
+
+    # JIS X 0201 katakana is U+FF61..U+FF9F
+    for c in range(0xA1, 0xE0):
+        glyphs.append((builtin[c], [0xFF61 + c - 0xA1]))
+
+    for code, rows in sorted(DRAWN.items()):
+        glyphs.append((parse(rows), [code]))
+
+    # Latin-1 letters
+    dotless_i = [0] * 5 + builtin[ord("i")][5:]
+    for letter, accent, code in LATIN1_LETTERS:
+        base = dotless_i if letter == "i" else builtin[ord(letter)]
+        top = 4 - len(ACCENTS[accent])
+        glyphs.append((overlay(base, ACCENTS[accent], top), [code]))
+        upper = letter.upper()
+        # capitals move down to make room above
+        base = shift_down(builtin[ord(upper)], 2)
+        glyphs.append((overlay(base, ACCENTS[accent], 0), [code - 0x20]))
+    glyphs.append((overlay(builtin[ord("c")], CEDILLA, 14), [0xE7]))
+    glyphs.append((overlay(builtin[ord("C")], CEDILLA, 14), [0xC7]))
+    slash = parse(["......#.", ".....#..", "....#...", "...#....", "..#.....", ".#......"])
+    o = list(builtin[ord("o")])
+    for i, row in enumerate(slash):
+        o[6 + i] |= row
+    glyphs.append((o, [0xF8]))
+    big_o = list(builtin[ord("O")])
+    for i, row in enumerate(slash):
+        big_o[2 + 2 * i] |= row
+        big_o[3 + 2 * i] |= row
+    glyphs.append((big_o, [0xD8]))
+
+    for code, (left, right, up, down) in sorted(BOX.items()):
+        glyphs.append((box(left, right, up, down), [code]))
+    for code, bitmap in sorted(blocks().items()):
+        glyphs.append((bitmap, [code]))
+    return glyphs
+
+
+WIDE_WIDTH = 16
+
+
+def centre(glyph):
+    # a narrow glyph in the middle of a wide one
+    return [row << 4 for row in glyph]
+
+
+def stretch(glyph):
+    # every pixel doubled horizontally
+    out = []
+    for row in glyph:
+        wide = 0
+        for x in range(WIDTH):
+            if row & (0x80 >> x):
+                wide |= 0xC000 >> (2 * x)
+        out.append(wide)
+    return out
+
+
+def mark(glyph):
+    # a half-width voiced sound mark moved to the right edge of a wide glyph
+    shift = min((row & -row).bit_length() - 1 for row in glyph if row)
+    return [row >> shift for row in glyph]
+
+
+def build_wide(builtin):
+    glyphs = []
+
+    # an empty box for everything the font doesn't have
+    tofu = [0] + [0x4002] * (HEIGHT - 3) + [0, 0]
+    tofu[1] = tofu[HEIGHT - 3] = 0x7FFE
+    glyphs.append((tofu, [0xFFFD]))
+    glyphs.append(([0] * HEIGHT, [0x3000]))
+
+    # U+FF01..U+FF5E are ASCII 0x21..0x7E
+    for c in range(0x21, 0x7F):
+        glyphs.append((centre(builtin[c]), [0xFF01 + c - 0x21]))
+    for code, narrow in ((0xFFE0, 0xA2), (0xFFE1, 0xA3), (0xFFE2, 0xAC), (0xFFE5, 0xA5)):
+        glyphs.append((centre(parse(DRAWN[narrow])), [code]))
+
+    # half-width forms U+FF61..U+FF9F are JIS X 0201 0xA1..0xDF
+    half = {ord(unicodedata.normalize("NFKC", chr(0xFF61 + i))): builtin[0xA1 + i]
+            for i in range(0x3F)}
+    # the full stop and comma sit at the bottom left, the brackets and dot stay thin
+    for code in (0x3001, 0x3002):
+        glyphs.append(([row << 8 for row in half[code]], [code]))
+    for code in (0x300C, 0x300D, 0x30FB):
+        glyphs.append((centre(half[code]), [code]))
+
+    marks = {0x3099: mark(half[0x3099]), 0x309A: mark(half[0x309A])}
+    for code in range(0x30A1, 0x30FD):
+        if code == 0x30FB:
+            continue
+        base, *rest = unicodedata.normalize("NFD", chr(code))
+        if ord(base) not in half:
+            continue
+        bitmap = stretch(half[ord(base)])
+        for combining in rest:
+            for y, row in enumerate(marks[ord(combining)]):
+                bitmap[y] |= row
+        glyphs.append((bitmap, [code]))
+    return glyphs
+
+
+def write_psf(path, glyphs, width=WIDTH):
+    bytes_per_row = (width + 7) // 8
+    header = struct.pack(
+        "<8I", PSF2_MAGIC, 0, 32, PSF2_HAS_UNICODE_TABLE, len(glyphs),
+        HEIGHT * bytes_per_row, HEIGHT, width
+    )
+    data = bytearray(header)
+    for bitmap, _ in glyphs:
+        for row in bitmap:
+            data += row.to_bytes(bytes_per_row, "big")
+    for _, codes in glyphs:
+        for code in codes:
+            data += chr(code).encode("utf-8")
+        data.append(PSF2_SEPARATOR)
+    with open(path, "wb") as f:
+        f.write(data)
+
+
+def main():
+    src = sys.argv[1] if len(sys.argv) > 1 else "src/ascii_font.rs"
+    dst = sys.argv[2] if len(sys.argv) > 2 else "fonts/rusmikan.psf"
+    wide_dst = sys.argv[3] if len(sys.argv) > 3 else "fonts/rusmikan-wide.psf"
+    builtin = load_builtin(src)
+    write_psf(dst, build(builtin))
+    write_psf(wide_dst, build_wide(builtin), WIDE_WIDTH)
+
+
+if __name__ == "__main__":
+    main()
@@ -1,11 +1,46 @@
 use crate::serial_println;
+use arrayvec::ArrayVec;
 use bit_field::BitField;
-use core::mem;
+use core::ops::Range;
+use core::{fmt, mem, ptr};
 use rsdp;
 use x86_64::instructions::port::Port;
 
 const PMTIMER_FREQ: usize = 3579545;
-pub static mut FADT_ADDR: u64 = 0;
+// What we need from the tables later, parsed once at init.
+static mut FADT_TABLE: Option<Fadt> = None;
+static mut MADT_INFO: Option<MadtInfo> = None;
+static mut HPET_INFO: Option<HpetInfo> = None;
+static mut S5_SLEEP_TYPES: Option<SleepTypes> = None;
+
+const MAX_TABLES: usize = 32;
+
+// Tables found in the XSDT, kept for listing.
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub signature: [u8; 4],
+    pub address: u64,
+    pub length: u32,
+    pub revision: u8,
+}
+
+static mut TABLES: ArrayVec<TableInfo, MAX_TABLES> = ArrayVec::new_const();
+
+pub fn tables() -> &'static [TableInfo] {
+    unsafe { &TABLES }
+}
+
+// the RSDP, the XSDT and the DSDT, which the XSDT doesn't list
+static mut ROOT_TABLES: ArrayVec<Range<usize>, 3> = ArrayVec::new_const();
+
+// Physical memory of every table we know of, kept out of the frame manager when
+// ACPI_RECLAIM memory is given back so that the tables stay readable.
+pub fn table_ranges() -> impl Iterator<Item = Range<usize>> {
+    let tables = tables()
+        .iter()
+        .map(|t| t.address as usize..(t.address + t.length as u64) as usize);
+    unsafe { ROOT_TABLES.iter().cloned() }.chain(tables)
+}
 
 // FIXME: want to use acpi crate with alloc
 struct Rsdp {
@@ -30,9 +65,13 @@ impl Rsdp {
 
 const XSDT: [u8; 4] = *b"XSDT";
 const FADT: [u8; 4] = *b"FACP";
+const MADT: [u8; 4] = *b"APIC";
+const HPET: [u8; 4] = *b"HPET";
+const SSDT: [u8; 4] = *b"SSDT";
 
 // https://docs.rs/acpi/latest/src/acpi/sdt.rs.html#100-110
 // 36 bytes
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct SdtHeader {
     pub signature: [u8; 4],
@@ -53,23 +92,114 @@ impl Flags {
     pub fn pm_timer_is_32_bit(&self) -> bool {
         self.0.get_bit(8)
     }
+
+    pub fn reset_register_supported(&self) -> bool {
+        self.0.get_bit(10)
+    }
 }
 
 // https://docs.rs/acpi/latest/src/acpi/fadt.rs.html#31-115
 // 276 bytes
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct Fadt {
     header: SdtHeader,
 
-    _reserved: [u8; 40], // mask un-used members
+    _reserved: [u8; 4], // mask un-used members
+
+    dsdt: u32,
+
+    _reserved4: [u8; 4], // mask un-used members
+
+    smi_command: u32,
+    acpi_enable: u8,
+
+    _reserved5: [u8; 11], // mask un-used members
+
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+
+    _reserved6: [u8; 4], // mask un-used members
 
     pm_timer_block: u32,
 
-    _reserved1: [u8; 32], // mask un-used members
+    _reserved1: [u8; 28], // mask un-used members
+
+    // CMOS RAM index of the RTC century, 0 if there is none
+    century: u8,
+
+    _reserved3: [u8; 3], // mask un-used members
 
     pub flags: Flags,
 
-    _reserved2: [u8; 160], // mask un-used members
+    // ACPI 2.0 and later from here on, check the table length
+    reset_register: GenericAddress,
+    reset_value: u8,
+
+    _reserved7: [u8; 11], // mask un-used members
+
+    x_dsdt: u64,
+
+    _reserved8: [u8; 24], // mask un-used members
+
+    x_pm1a_control_block: GenericAddress,
+    x_pm1b_control_block: GenericAddress,
+
+    _reserved2: [u8; 80], // mask un-used members
+}
+
+// offsets of the fields added after ACPI 1.0
+const FADT_RESET_VALUE_END: u32 = 129;
+const FADT_X_DSDT_END: u32 = 148;
+const FADT_X_PM1B_CONTROL_BLOCK_END: u32 = 196;
+
+impl Fadt {
+    fn has(&self, end: u32) -> bool {
+        self.header.length >= end
+    }
+
+    fn dsdt_address(&self) -> u64 {
+        if self.has(FADT_X_DSDT_END) && { self.x_dsdt } != 0 {
+            self.x_dsdt
+        } else {
+            self.dsdt as u64
+        }
+    }
+
+    // Port the ACPI_ENABLE command is written to, or None if ACPI mode is fixed.
+    pub fn acpi_enable_command(&self) -> Option<(u16, u8)> {
+        if self.smi_command == 0 || self.acpi_enable == 0 {
+            return None;
+        }
+        Some((self.smi_command as u16, self.acpi_enable))
+    }
+
+    // PM1a and PM1b control registers. PM1b is optional.
+    pub fn pm1_control_blocks(&self) -> (Option<Register>, Option<Register>) {
+        let legacy = |port: u32| (port != 0).then_some(Register::Io(port as u16));
+        if self.has(FADT_X_PM1B_CONTROL_BLOCK_END) {
+            let (a, b) = (self.x_pm1a_control_block, self.x_pm1b_control_block);
+            if let Some(a) = a.register() {
+                return (Some(a), b.register());
+            }
+        }
+        (
+            legacy(self.pm1a_control_block),
+            legacy(self.pm1b_control_block),
+        )
+    }
+
+    // Register and value that reset the machine when written.
+    pub fn reset_register(&self) -> Option<(Register, u8)> {
+        let flags = self.flags;
+        if !self.has(FADT_RESET_VALUE_END) || !flags.reset_register_supported() {
+            return None;
+        }
+        let reset_register = self.reset_register;
+        reset_register
+            .register()
+            .map(|register| (register, self.reset_value))
+    }
 }
 
 pub unsafe fn init_rsdp(addr: u64) {
@@ -79,6 +209,8 @@ pub unsafe fn init_rsdp(addr: u64) {
     }
     let header = rsdp.xsdt_address();
     let length = (*(header as *const SdtHeader)).length;
+    let _ = ROOT_TABLES.try_push(addr as usize..addr as usize + mem::size_of::<rsdp::Rsdp>());
+    let _ = ROOT_TABLES.try_push(header as usize..(header + length as u64) as usize);
     let sig = (*(header as *const SdtHeader)).signature;
     serial_println!("{:?}", length);
     serial_println!("{:?}", sig);
@@ -90,25 +222,472 @@ pub unsafe fn init_rsdp(addr: u64) {
     serial_println!("{:?}", num_tables);
     let tables_base = (header as usize + mem::size_of::<SdtHeader>()) as *const u64;
     for i in 0..num_tables {
-        let addr = tables_base.add(i);
-        serial_println!("{:?}, {:?}", addr, *addr);
-        let sig = (*(*addr as *const SdtHeader)).signature;
+        // entries start at offset 36 and are not 8-byte aligned
+        let addr = ptr::read_unaligned(tables_base.add(i));
+        serial_println!("{:?}, {:?}", tables_base.add(i), addr);
+        let header = ptr::read_unaligned(addr as *const SdtHeader);
+        let sig = header.signature;
         serial_println!("{:?}", sig);
+        let _ = TABLES.try_push(TableInfo {
+            signature: sig,
+            address: addr,
+            length: header.length,
+            revision: header.revision,
+        });
         if sig == FADT {
             serial_println!("It is FADT");
-            FADT_ADDR = *addr;
+            FADT_TABLE = Some(copy_table(addr));
+        } else if sig == MADT {
+            serial_println!("It is MADT");
+            MADT_INFO = Some(parse_madt(addr));
+        } else if sig == HPET {
+            serial_println!("It is HPET");
+            HPET_INFO = parse_hpet(addr);
+        }
+    }
+
+    // \_S5 is normally in the DSDT, but may come with an SSDT
+    if let Some(fadt) = FADT_TABLE {
+        let dsdt = fadt.dsdt_address();
+        let dsdt_length = ptr::read_unaligned(dsdt as *const SdtHeader).length;
+        let _ = ROOT_TABLES.try_push(dsdt as usize..(dsdt + dsdt_length as u64) as usize);
+        let ssdts = TABLES
+            .iter()
+            .filter(|t| t.signature == SSDT)
+            .map(|t| t.address);
+        S5_SLEEP_TYPES = core::iter::once(dsdt)
+            .chain(ssdts)
+            .find_map(|addr| find_s5(addr));
+        serial_println!("ACPI: _S5 {:?}", S5_SLEEP_TYPES);
+    }
+}
+
+// Copy a table out of firmware memory. Tables of older revisions are shorter than `T`,
+// so only `length` bytes are read and the fields they lack are left zero.
+unsafe fn copy_table<T: Copy>(addr: u64) -> T {
+    let length = ptr::read_unaligned(addr as *const SdtHeader).length as usize;
+    let mut table = mem::MaybeUninit::<T>::zeroed();
+    ptr::copy_nonoverlapping(
+        addr as *const u8,
+        table.as_mut_ptr() as *mut u8,
+        length.min(mem::size_of::<T>()),
+    );
+    table.assume_init()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    unsafe { FADT_TABLE.as_ref() }
+}
+
+pub fn rtc_century_register() -> Option<u8> {
+    unsafe {
+        FADT_TABLE
+            .map(|fadt| fadt.century)
+            .filter(|&century| century != 0)
+    }
+}
+
+pub fn madt() -> &'static MadtInfo {
+    unsafe { MADT_INFO.as_ref().expect("MADT is not found") }
+}
+
+// https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#multiple-apic-description-table-madt
+#[repr(C, packed)]
+struct Madt {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+    // followed by variable length Interrupt Controller Structures
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_NMI_SOURCE: u8 = 3;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_LOCAL_X2APIC_NMI: u8 = 10;
+
+pub const MAX_PROCESSORS: usize = 64;
+pub const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+const MAX_NMIS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+// MPS INTI flags shared by overrides and NMI entries.
+// "conforms to the bus" is resolved to ISA semantics (active high, edge triggered).
+#[derive(Debug, Clone, Copy)]
+pub struct IntiFlags(u16);
+
+impl IntiFlags {
+    pub fn polarity(&self) -> Polarity {
+        match self.0.get_bits(0..2) {
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        }
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        match self.0.get_bits(2..4) {
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: IntiFlags,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub flags: IntiFlags,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    // 0xff means all processors
+    pub processor_id: u8,
+    pub lint: u8,
+    pub flags: IntiFlags,
+}
+
+#[derive(Debug)]
+pub struct MadtInfo {
+    pub local_apic_address: u64,
+    pub processors: ArrayVec<Processor, MAX_PROCESSORS>,
+    pub io_apics: ArrayVec<IoApicEntry, MAX_IO_APICS>,
+    pub overrides: ArrayVec<InterruptSourceOverride, MAX_OVERRIDES>,
+    pub nmi_sources: ArrayVec<NmiSource, MAX_NMIS>,
+    pub local_apic_nmis: ArrayVec<LocalApicNmi, MAX_NMIS>,
+}
+
+impl MadtInfo {
+    // Translate an ISA IRQ into its Global System Interrupt and flags.
+    pub fn irq_to_gsi(&self, irq: u8) -> (u32, IntiFlags) {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map(|o| (o.gsi, o.flags))
+            .unwrap_or((irq as u32, IntiFlags(0)))
+    }
+}
+
+fn push_entry<T: fmt::Debug, const N: usize>(list: &mut ArrayVec<T, N>, entry: T) {
+    if let Err(err) = list.try_push(entry) {
+        serial_println!(
+            "MADT: more than {} entries, {:x?} is skipped",
+            N,
+            err.element()
+        );
+    }
+}
+
+unsafe fn parse_madt(addr: u64) -> MadtInfo {
+    let madt = ptr::read_unaligned(addr as *const Madt);
+    let mut info = MadtInfo {
+        local_apic_address: madt.local_apic_address as u64,
+        processors: ArrayVec::new(),
+        io_apics: ArrayVec::new(),
+        overrides: ArrayVec::new(),
+        nmi_sources: ArrayVec::new(),
+        local_apic_nmis: ArrayVec::new(),
+    };
+
+    let read_u16 = |p: u64| ptr::read_unaligned(p as *const u16);
+    let read_u32 = |p: u64| ptr::read_unaligned(p as *const u32);
+    let read_u64 = |p: u64| ptr::read_unaligned(p as *const u64);
+
+    let end = addr + madt.header.length as u64;
+    let mut entry = addr + mem::size_of::<Madt>() as u64;
+    while entry + 2 <= end {
+        let entry_type = *(entry as *const u8);
+        let length = *((entry + 1) as *const u8) as u64;
+        if length < 2 {
             break;
         }
+        match entry_type {
+            MADT_LOCAL_APIC => push_entry(
+                &mut info.processors,
+                Processor {
+                    processor_id: *((entry + 2) as *const u8),
+                    apic_id: *((entry + 3) as *const u8),
+                    enabled: read_u32(entry + 4).get_bit(0),
+                },
+            ),
+            MADT_IO_APIC => push_entry(
+                &mut info.io_apics,
+                IoApicEntry {
+                    id: *((entry + 2) as *const u8),
+                    address: read_u32(entry + 4),
+                    gsi_base: read_u32(entry + 8),
+                },
+            ),
+            MADT_INTERRUPT_SOURCE_OVERRIDE => push_entry(
+                &mut info.overrides,
+                InterruptSourceOverride {
+                    bus: *((entry + 2) as *const u8),
+                    source: *((entry + 3) as *const u8),
+                    gsi: read_u32(entry + 4),
+                    flags: IntiFlags(read_u16(entry + 8)),
+                },
+            ),
+            MADT_NMI_SOURCE => push_entry(
+                &mut info.nmi_sources,
+                NmiSource {
+                    flags: IntiFlags(read_u16(entry + 2)),
+                    gsi: read_u32(entry + 4),
+                },
+            ),
+            MADT_LOCAL_APIC_NMI => push_entry(
+                &mut info.local_apic_nmis,
+                LocalApicNmi {
+                    processor_id: *((entry + 2) as *const u8),
+                    flags: IntiFlags(read_u16(entry + 3)),
+                    lint: *((entry + 5) as *const u8),
+                },
+            ),
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                info.local_apic_address = read_u64(entry + 4);
+            }
+            // Only xAPIC mode is supported. Firmware lists processors with APIC IDs
+            // above 254 this way, and they stay offline.
+            MADT_LOCAL_X2APIC => {
+                serial_println!(
+                    "MADT: x2APIC processor (x2APIC ID {}) is skipped",
+                    read_u32(entry + 4)
+                );
+            }
+            MADT_LOCAL_X2APIC_NMI => {
+                serial_println!("MADT: x2APIC NMI entry is skipped");
+            }
+            _ => {
+                serial_println!("MADT: entry type {} is skipped", entry_type);
+            }
+        }
+        entry += length;
+    }
+
+    serial_println!("{:x?}", info);
+    info
+}
+
+// https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#generic-address-structure-gas
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct GenericAddress {
+    address_space_id: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+// A fixed hardware register described by a Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Io(u16),
+    // physical address, inside the identity map
+    Memory(u64),
+}
+
+impl GenericAddress {
+    fn register(&self) -> Option<Register> {
+        let address = self.address;
+        match self.address_space_id {
+            _ if address == 0 => None,
+            GAS_SYSTEM_IO => Some(Register::Io(address as u16)),
+            GAS_SYSTEM_MEMORY => Some(Register::Memory(address)),
+            // PCI configuration space and the rest are not supported
+            _ => None,
+        }
     }
 }
 
+impl Register {
+    pub unsafe fn read_u16(self) -> u16 {
+        match self {
+            Register::Io(port) => Port::<u16>::new(port).read(),
+            Register::Memory(addr) => ptr::read_volatile(addr as *const u16),
+        }
+    }
+
+    pub unsafe fn write_u16(self, value: u16) {
+        match self {
+            Register::Io(port) => Port::<u16>::new(port).write(value),
+            Register::Memory(addr) => ptr::write_volatile(addr as *mut u16, value),
+        }
+    }
+
+    pub unsafe fn write_u8(self, value: u8) {
+        match self {
+            Register::Io(port) => Port::<u8>::new(port).write(value),
+            Register::Memory(addr) => ptr::write_volatile(addr as *mut u8, value),
+        }
+    }
+}
+
+// IA-PC HPET (High Precision Event Timers) Specification 1.0a, 3.2.4
+// 56 bytes
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Hpet {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    min_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HpetInfo {
+    // physical address of the register block
+    pub address: u64,
+    pub hpet_number: u8,
+    // smallest periodic interval in main counter ticks that does not lose interrupts
+    pub min_tick: u16,
+}
+
+pub fn hpet() -> Option<&'static HpetInfo> {
+    unsafe { HPET_INFO.as_ref() }
+}
+
+unsafe fn parse_hpet(addr: u64) -> Option<HpetInfo> {
+    let hpet = ptr::read_unaligned(addr as *const Hpet);
+    let base_address = hpet.base_address;
+    if base_address.address_space_id != GAS_SYSTEM_MEMORY {
+        return None;
+    }
+    let info = HpetInfo {
+        address: base_address.address,
+        hpet_number: hpet.hpet_number,
+        min_tick: hpet.min_tick,
+    };
+    serial_println!("{:x?}", info);
+    Some(info)
+}
+
+// SLP_TYPa and SLP_TYPb values for a sleep state, written to PM1a and PM1b control
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepTypes {
+    pub a: u8,
+    pub b: u8,
+}
+
+pub fn s5_sleep_types() -> Option<SleepTypes> {
+    unsafe { S5_SLEEP_TYPES }
+}
+
+// AML opcodes
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+// Find `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` in the AML of a DSDT or SSDT.
+// There is no AML interpreter, so an _S5 that is a method or computed is not found.
+// refs. https://uefi.org/specs/ACPI/6.5/20_AML_Specification.html
+unsafe fn find_s5(addr: u64) -> Option<SleepTypes> {
+    let header = ptr::read_unaligned(addr as *const SdtHeader);
+    let header_size = mem::size_of::<SdtHeader>();
+    let length = (header.length as usize).checked_sub(header_size)?;
+    let aml = core::slice::from_raw_parts((addr as usize + header_size) as *const u8, length);
+
+    for i in 0..aml.len().saturating_sub(4) {
+        if &aml[i..i + 4] != b"_S5_" {
+            continue;
+        }
+        // NameOp, with or without the root prefix
+        let name_op = matches!(
+            aml[..i],
+            [.., AML_NAME_OP, AML_ROOT_CHAR] | [.., AML_NAME_OP]
+        );
+        let mut p = i + 4;
+        if !name_op || aml.get(p) != Some(&AML_PACKAGE_OP) {
+            continue;
+        }
+        // PkgLength: bits 6-7 of the lead byte count the bytes that follow
+        let lead = *aml.get(p + 1)?;
+        p += 2 + (lead >> 6) as usize;
+        // NumElements
+        p += 1;
+        let a = aml_integer(aml, &mut p)?;
+        let b = aml_integer(aml, &mut p)?;
+        // SLP_TYP is 3 bits wide
+        return Some(SleepTypes {
+            a: a as u8 & 0x7,
+            b: b as u8 & 0x7,
+        });
+    }
+    None
+}
+
+// Decode a constant integer object at `p` and move past it.
+fn aml_integer(aml: &[u8], p: &mut usize) -> Option<u32> {
+    let (value, size) = match *aml.get(*p)? {
+        AML_ZERO_OP => (0, 1),
+        AML_ONE_OP => (1, 1),
+        AML_BYTE_PREFIX => (*aml.get(*p + 1)? as u32, 2),
+        AML_WORD_PREFIX => {
+            let bytes = aml.get(*p + 1..*p + 3)?;
+            (u16::from_le_bytes([bytes[0], bytes[1]]) as u32, 3)
+        }
+        AML_DWORD_PREFIX => {
+            let bytes = aml.get(*p + 1..*p + 5)?;
+            (u32::from_le_bytes(bytes.try_into().ok()?), 5)
+        }
+        _ => return None,
+    };
+    *p += size;
+    Some(value)
+}
+
 pub unsafe fn wait_milliseconds_with_pm_timer(msec: u32) {
-    let mut timer = Port::<u32>::new((*(FADT_ADDR as *const Fadt)).pm_timer_block as u16);
+    let fadt = FADT_TABLE.expect("FADT is not found");
+    let mut timer = Port::<u32>::new(fadt.pm_timer_block as u16);
     let start = timer.read();
-    serial_println!("{:?}", start);
     let mut end = start.wrapping_add((PMTIMER_FREQ * msec as usize / 1000) as u32);
 
-    let flags = (*(FADT_ADDR as *const Fadt)).flags;
+    let flags = fadt.flags;
     if !flags.pm_timer_is_32_bit() {
         end &= 0x00ffffff;
     }
@@ -4,10 +4,7 @@ use core::{
 };
 use x86_64::VirtAddr;
 
-use crate::{
-    frame::{BITMAP_FRAME_MANAGER, FRAME_BYTES},
-    panic, serial_println,
-};
+use crate::frame::{FRAME_BYTES, FRAME_MANAGER};
 use core::ptr;
 
 #[global_allocator]
@@ -37,18 +34,19 @@ impl KernelAllocator {
         let block_size = BLOCK_SIZES[index];
         let num_blocks_per_frame = FRAME_BYTES / block_size;
 
-        let ptr: *mut u8 = match BITMAP_FRAME_MANAGER.allocate(1) {
+        let ptr: *mut u8 = match FRAME_MANAGER.lock().allocate(1) {
             Some(frame) => VirtAddr::new((frame * FRAME_BYTES) as u64).as_u64() as *mut u8,
             None => return ptr::null_mut(),
         };
-        for i in (0..num_blocks_per_frame).rev() {
-            let current = ptr.add(i * block_size);
-            let next = current.add(block_size) as *mut ListNode;
+        // The first block is handed to the caller, the rest are pushed onto the free list.
+        // Nodes must stay inside this frame since the next frame may belong to someone else.
+        for i in (1..num_blocks_per_frame).rev() {
+            let current = ptr.add(i * block_size) as *mut ListNode;
             let new_node = ListNode {
                 next: self.list_heads[index].take(),
             };
-            next.write(new_node);
-            self.list_heads[index] = Some(&mut *next);
+            current.write(new_node);
+            self.list_heads[index] = Some(&mut *current);
         }
         ptr
     }
@@ -59,35 +57,35 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+// Objects that do not fit in any block size are served directly from the frame manager
+// as a run of contiguous frames.
+fn frames_for_large_object(layout: &Layout) -> usize {
+    (layout.size() + FRAME_BYTES - 1) / FRAME_BYTES
+}
+
 unsafe impl GlobalAlloc for Locked<KernelAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
         match list_index(&layout) {
-            Some(index) => {
-                serial_println!("DEBUG: alloc size: {:?}", BLOCK_SIZES[index]);
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        let ptr = allocator.allocate_frame_for_block(index);
-                        ptr as *mut ListNode as *mut u8
-                    }
+            Some(index) => match allocator.list_heads[index].take() {
+                Some(node) => {
+                    allocator.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
                 }
-            }
+                None => {
+                    let ptr = allocator.allocate_frame_for_block(index);
+                    ptr as *mut ListNode as *mut u8
+                }
+            },
             None => {
-                // TODO: support > 4096 size allocation
-                serial_println!("No index. allocate frame {:?}", layout.size());
-                if layout.size() == FRAME_BYTES {
-                    match BITMAP_FRAME_MANAGER.allocate(layout.size() / FRAME_BYTES) {
-                        Some(frame) => {
-                            VirtAddr::new((frame * FRAME_BYTES) as u64).as_u64() as *mut u8
-                        }
-                        None => panic!("Out Of Memory"),
-                    }
-                } else {
-                    panic!("heap allocation over 4 KiB is not supported!");
+                let num_frames = frames_for_large_object(&layout);
+                let align_frames = (layout.align() / FRAME_BYTES).max(1);
+                match FRAME_MANAGER
+                    .lock()
+                    .allocate_aligned(num_frames, align_frames)
+                {
+                    Some(frame) => VirtAddr::new((frame * FRAME_BYTES) as u64).as_u64() as *mut u8,
+                    None => ptr::null_mut(),
                 }
             }
         }
@@ -106,8 +104,10 @@ unsafe impl GlobalAlloc for Locked<KernelAllocator> {
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
             }
             None => {
-                // TODO: support > 4096 size allocation
-                BITMAP_FRAME_MANAGER.free(ptr as usize / FRAME_BYTES, 1);
+                let num_frames = frames_for_large_object(&layout);
+                FRAME_MANAGER
+                    .lock()
+                    .free(ptr as usize / FRAME_BYTES, num_frames);
             }
         }
     }
@@ -0,0 +1,138 @@
+use crate::graphics::Rgb;
+use arrayvec::ArrayVec;
+
+const MAX_PARAMS: usize = 16;
+
+// What the console should do with the characters fed to `Parser::advance`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    // C0 control character such as '\n', '\r', '\t' or '\u{8}'
+    Control(char),
+    // Control Sequence Introducer: ESC [ params final
+    Csi {
+        params: ArrayVec<u16, MAX_PARAMS>,
+        final_byte: char,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+// Splits a character stream into printable characters, controls and escape sequences.
+// Sequences other than CSI are consumed and dropped.
+pub struct Parser {
+    state: State,
+    params: ArrayVec<u16, MAX_PARAMS>,
+    current: Option<u16>,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            params: ArrayVec::new_const(),
+            current: None,
+        }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\0'..='\x1f' | '\x7f' => Some(Action::Control(c)),
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => {
+                self.state = if c == '[' {
+                    self.params.clear();
+                    self.current = None;
+                    State::Csi
+                } else {
+                    State::Ground
+                };
+                None
+            }
+            State::Csi => match c {
+                '0'..='9' => {
+                    let digit = c as u16 - '0' as u16;
+                    let value = self.current.unwrap_or(0);
+                    self.current = Some(value.saturating_mul(10).saturating_add(digit));
+                    None
+                }
+                ';' | ':' => {
+                    // an omitted parameter is 0
+                    let _ = self.params.try_push(self.current.take().unwrap_or(0));
+                    None
+                }
+                // private markers and intermediates are accepted and ignored
+                '<'..='?' | ' '..='/' => None,
+                '@'..='~' => {
+                    if let Some(value) = self.current.take() {
+                        let _ = self.params.try_push(value);
+                    }
+                    self.state = State::Ground;
+                    Some(Action::Csi {
+                        params: core::mem::take(&mut self.params),
+                        final_byte: c,
+                    })
+                }
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+}
+
+const fn rgb(r: u8, g: u8, b: u8) -> Rgb {
+    Rgb { r, g, b }
+}
+
+// The 16 basic colours, normal then bright (xterm defaults).
+const BASIC_COLORS: [Rgb; 16] = [
+    rgb(0, 0, 0),
+    rgb(205, 0, 0),
+    rgb(0, 205, 0),
+    rgb(205, 205, 0),
+    rgb(0, 0, 238),
+    rgb(205, 0, 205),
+    rgb(0, 205, 205),
+    rgb(229, 229, 229),
+    rgb(127, 127, 127),
+    rgb(255, 0, 0),
+    rgb(0, 255, 0),
+    rgb(255, 255, 0),
+    rgb(92, 92, 255),
+    rgb(255, 0, 255),
+    rgb(0, 255, 255),
+    rgb(255, 255, 255),
+];
+
+pub fn basic_color(index: usize, bright: bool) -> Rgb {
+    BASIC_COLORS[index % 8 + if bright { 8 } else { 0 }]
+}
+
+// xterm 256-colour palette: 16 basic colours, a 6x6x6 cube and 24 greys.
+pub fn palette_256(index: u8) -> Rgb {
+    match index {
+        0..=15 => BASIC_COLORS[index as usize],
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let i = index - 16;
+            rgb(level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        232..=255 => {
+            let v = 8 + (index - 232) * 10;
+            rgb(v, v, v)
+        }
+    }
+}
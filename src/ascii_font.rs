@@ -1,5 +1,5 @@
 // copy from https://github.com/skoji/laranja-os.git
-pub const FONTS: [[u8; 16]; 256] = [
+pub static FONTS: [[u8; 16]; 256] = [
     [
         0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,
         0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,
@@ -0,0 +1,122 @@
+use crate::paging::translate_addr;
+use arrayvec::ArrayVec;
+use core::arch::asm;
+use core::fmt;
+use core::hint::black_box;
+use core::str;
+use x86_64::VirtAddr;
+
+const MAX_DEPTH: usize = 64;
+const KSYMTAB_SIZE: usize = 1024 * 1024;
+
+// Symbol table of the kernel itself, filled in after linking by scripts/embed_symbols.sh,
+// which `cargo run` calls as the runner (.cargo/config.toml).
+// Each line is "<hex address> <demangled name>", sorted by address and terminated by NUL.
+#[used]
+#[link_section = ".ksymtab"]
+static KSYMTAB: [u8; KSYMTAB_SIZE] = [0; KSYMTAB_SIZE];
+
+fn symbol_table() -> &'static str {
+    // the contents are patched after compilation, so do not let the compiler see the zeros
+    let table = unsafe { &*black_box(&KSYMTAB as *const [u8; KSYMTAB_SIZE]) };
+    let len = table.iter().position(|&b| b == 0).unwrap_or(table.len());
+    str::from_utf8(&table[..len]).unwrap_or("")
+}
+
+// False when the kernel was built without running scripts/embed_symbols.sh.
+pub fn has_symbols() -> bool {
+    !symbol_table().is_empty()
+}
+
+// Find the symbol containing `addr` and the offset from its start.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let mut found = None;
+    for line in symbol_table().lines() {
+        let (sym_addr, name) = match line.split_once(' ') {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let sym_addr = match u64::from_str_radix(sym_addr, 16) {
+            Ok(sym_addr) => sym_addr,
+            Err(_) => continue,
+        };
+        if sym_addr > addr {
+            break;
+        }
+        found = Some((name, addr - sym_addr));
+    }
+    found
+}
+
+fn is_valid_frame_pointer(rbp: u64) -> bool {
+    if rbp == 0 || rbp % 8 != 0 {
+        return false;
+    }
+    match VirtAddr::try_new(rbp) {
+        Ok(addr) => translate_addr(addr).is_some() && translate_addr(addr + 8u64).is_some(),
+        Err(_) => false,
+    }
+}
+
+// Return addresses of a call stack, innermost first.
+pub struct Backtrace {
+    frames: ArrayVec<u64, MAX_DEPTH>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !has_symbols() {
+            writeln!(f, "  (symbols not embedded, see scripts/embed_symbols.sh)")?;
+        }
+        for (depth, &addr) in self.frames.iter().enumerate() {
+            let (name, offset) = resolve(addr).unwrap_or(("<unknown>", 0));
+            writeln!(f, "  {:2}: {:#018x} - {}+{:#x}", depth, addr, name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+// Walk the chain of saved frame pointers. Requires "frame-pointer": "always" in the target spec.
+//
+// rbp -> [saved rbp of the caller]
+//        [return address]
+unsafe fn walk(mut rbp: u64, frames: &mut ArrayVec<u64, MAX_DEPTH>) {
+    while !frames.is_full() && is_valid_frame_pointer(rbp) {
+        let return_addr = *((rbp + 8) as *const u64);
+        if return_addr == 0 {
+            break;
+        }
+        // the return address points after the call instruction
+        frames.push(return_addr - 1);
+        rbp = *(rbp as *const u64);
+    }
+}
+
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    rbp
+}
+
+// The call stack above the function this is inlined into, starting at its caller.
+#[inline(always)]
+pub fn capture() -> Backtrace {
+    let mut frames = ArrayVec::new();
+    unsafe { walk(frame_pointer(), &mut frames) };
+    Backtrace { frames }
+}
+
+// The call stack of the code interrupted by an exception.
+// Must be called from a function called directly by the `extern "x86-interrupt"` handler:
+// its frame pointer is saved in the handler's frame, which in turn saved the interrupted one.
+#[inline(always)]
+pub unsafe fn capture_exception(instruction_pointer: u64) -> Backtrace {
+    let mut frames = ArrayVec::new();
+    frames.push(instruction_pointer);
+    let handler_rbp = *(frame_pointer() as *const u64);
+    if is_valid_frame_pointer(handler_rbp) {
+        walk(*(handler_rbp as *const u64), &mut frames);
+    }
+    Backtrace { frames }
+}
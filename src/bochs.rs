@@ -0,0 +1,213 @@
+use crate::console;
+use crate::graphics::{Graphic, PixelLayout, GRAPHIC};
+use crate::layer::LAYER_MANAGER;
+use crate::mouse;
+use crate::paging;
+use crate::pci;
+use crate::serial_println;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// Bochs / QEMU standard VGA with the DISPI (VBE extensions) interface
+// refs. https://wiki.osdev.org/Bochs_VBE_Extensions
+const VENDOR_ID: u16 = 0x1234;
+const DEVICE_ID: u16 = 0x1111;
+
+const DISPI_INDEX_PORT: u16 = 0x01ce;
+const DISPI_DATA_PORT: u16 = 0x01cf;
+
+const DISPI_INDEX_ID: u16 = 0x0;
+const DISPI_INDEX_XRES: u16 = 0x1;
+const DISPI_INDEX_YRES: u16 = 0x2;
+const DISPI_INDEX_BPP: u16 = 0x3;
+const DISPI_INDEX_ENABLE: u16 = 0x4;
+const DISPI_INDEX_VIRT_WIDTH: u16 = 0x6;
+const DISPI_INDEX_VIRT_HEIGHT: u16 = 0x7;
+const DISPI_INDEX_X_OFFSET: u16 = 0x8;
+const DISPI_INDEX_Y_OFFSET: u16 = 0x9;
+const DISPI_INDEX_VIDEO_MEMORY_64K: u16 = 0xa;
+
+// 32 bpp and the linear framebuffer need at least version 0xb0c2,
+// reading the maximum resolution (GETCAPS) 0xb0c4
+const DISPI_ID2: u16 = 0xb0c2;
+const DISPI_ID4: u16 = 0xb0c4;
+const DISPI_ID5: u16 = 0xb0c5;
+
+const DISPI_DISABLED: u16 = 0x00;
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_GETCAPS: u16 = 0x02;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+// the VGA BIOS default when the VIDEO_MEMORY_64K register is missing
+const DEFAULT_VRAM_BYTES: usize = 4 * 1024 * 1024;
+const BYTES_PER_PIXEL: usize = 4;
+
+// Offered by `modes`, as far as the adapter and its memory allow.
+const STANDARD_MODES: [(usize, usize); 9] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 1024),
+    (1366, 768),
+    (1600, 900),
+    (1920, 1080),
+    (2560, 1440),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeError {
+    NoDevice,
+    Unsupported,
+}
+
+struct BochsVga {
+    // linear framebuffer (BAR 0), mapped for all of `vram_bytes`
+    framebuffer: *mut u8,
+    vram_bytes: usize,
+    max_width: usize,
+    max_height: usize,
+}
+
+static mut BOCHS_VGA: Option<BochsVga> = None;
+
+unsafe fn read_reg(index: u16) -> u16 {
+    Port::<u16>::new(DISPI_INDEX_PORT).write(index);
+    Port::<u16>::new(DISPI_DATA_PORT).read()
+}
+
+unsafe fn write_reg(index: u16, value: u16) {
+    Port::<u16>::new(DISPI_INDEX_PORT).write(index);
+    Port::<u16>::new(DISPI_DATA_PORT).write(value);
+}
+
+// Look for the adapter on the PCI bus. GRAPHIC is left alone until `set_mode`.
+pub unsafe fn init() {
+    let device = match pci::find_device(VENDOR_ID, DEVICE_ID) {
+        Some(device) => device,
+        None => return,
+    };
+    let id = read_reg(DISPI_INDEX_ID);
+    if !(DISPI_ID2..=DISPI_ID5).contains(&id) {
+        serial_println!("bochs: unsupported DISPI version {:#x}", id);
+        return;
+    }
+
+    let vram_bytes = match read_reg(DISPI_INDEX_VIDEO_MEMORY_64K) {
+        0 => DEFAULT_VRAM_BYTES,
+        blocks => blocks as usize * 64 * 1024,
+    };
+    let phys = device.read_bar(0);
+    // usually inside the identity map, where the loader's framebuffer is used as is
+    let framebuffer = if phys + vram_bytes as u64 <= paging::IDENTITY_MAP_END {
+        phys as *mut u8
+    } else {
+        match paging::map_mmio(phys, vram_bytes as u64) {
+            Ok(virt) => virt.as_mut_ptr(),
+            Err(err) => {
+                serial_println!("bochs: failed to map the framebuffer: {:?}", err);
+                return;
+            }
+        }
+    };
+
+    // With GETCAPS set the resolution registers read back the maximum instead.
+    let (max_width, max_height) = if id >= DISPI_ID4 {
+        let enable = read_reg(DISPI_INDEX_ENABLE);
+        write_reg(DISPI_INDEX_ENABLE, enable | DISPI_GETCAPS);
+        let caps = (read_reg(DISPI_INDEX_XRES), read_reg(DISPI_INDEX_YRES));
+        write_reg(DISPI_INDEX_ENABLE, enable);
+        (caps.0 as usize, caps.1 as usize)
+    } else {
+        (1024, 768)
+    };
+
+    serial_println!(
+        "bochs: DISPI {:#x}, {} KiB VRAM at {:#x}, up to {}x{}",
+        id,
+        vram_bytes / 1024,
+        phys,
+        max_width,
+        max_height
+    );
+    BOCHS_VGA = Some(BochsVga {
+        framebuffer,
+        vram_bytes,
+        max_width,
+        max_height,
+    });
+}
+
+pub fn modes() -> Vec<Mode> {
+    let vga = match unsafe { BOCHS_VGA.as_ref() } {
+        Some(vga) => vga,
+        None => return Vec::new(),
+    };
+    STANDARD_MODES
+        .iter()
+        .map(|&(width, height)| Mode { width, height })
+        .filter(|mode| {
+            mode.width <= vga.max_width
+                && mode.height <= vga.max_height
+                && mode.width * mode.height * BYTES_PER_PIXEL <= vga.vram_bytes
+        })
+        .collect()
+}
+
+pub fn current_mode() -> Option<Mode> {
+    unsafe { GRAPHIC.as_ref() }.map(|graphic| Mode {
+        width: graphic.horizontal_resolution(),
+        height: graphic.vertical_resolution(),
+    })
+}
+
+// Switch to `mode` at 32 bpp, point GRAPHIC at the linear framebuffer and
+// redraw every layer and the console at the new size.
+pub fn set_mode(mode: Mode) -> Result<(), ModeError> {
+    let vga = unsafe { BOCHS_VGA.as_ref() }.ok_or(ModeError::NoDevice)?;
+    if !modes().contains(&mode) {
+        return Err(ModeError::Unsupported);
+    }
+
+    {
+        // nobody may draw while GRAPHIC is replaced
+        let mut layers = LAYER_MANAGER.lock();
+        let stride = unsafe {
+            write_reg(DISPI_INDEX_ENABLE, DISPI_DISABLED);
+            write_reg(DISPI_INDEX_XRES, mode.width as u16);
+            write_reg(DISPI_INDEX_YRES, mode.height as u16);
+            write_reg(DISPI_INDEX_BPP, (BYTES_PER_PIXEL * 8) as u16);
+            write_reg(DISPI_INDEX_VIRT_WIDTH, mode.width as u16);
+            write_reg(DISPI_INDEX_VIRT_HEIGHT, mode.height as u16);
+            write_reg(DISPI_INDEX_X_OFFSET, 0);
+            write_reg(DISPI_INDEX_Y_OFFSET, 0);
+            write_reg(DISPI_INDEX_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+            // the adapter may round the line length up
+            read_reg(DISPI_INDEX_VIRT_WIDTH) as usize
+        };
+        // 32 bpp pixels are blue, green, red, unused in memory
+        let layout = PixelLayout::from_masks(0xff_0000, 0xff00, 0xff, 0xff00_0000);
+        let graphic =
+            Graphic::with_framebuffer(vga.framebuffer, mode.width, mode.height, stride, layout);
+        // The mouse interrupt reads GRAPHIC for the screen size, so it must not see the
+        // swap half done nor a position outside the new screen. The old buffers are
+        // freed once interrupts are back on.
+        let old = interrupts::without_interrupts(|| {
+            let old = unsafe { GRAPHIC.replace(graphic) };
+            mouse::clamp_position(mode.width, mode.height);
+            old
+        });
+        drop(old);
+        layers.resize_screen(mode.width, mode.height);
+        layers.flush();
+    }
+    console::relayout();
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use core::ptr;
+use rusmikan::MemoryMap;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use crate::frame::{
+    for_each_range, frame_end, Usage, BITS_PER_MAP_LINE, FRAME_BYTES, FRAME_COUNTS, FRAME_MIN,
+};
+
+// Largest block is 2^MAX_ORDER frames (64 MiB), so that full-screen pixel buffers
+// up to 4K resolution can be allocated in one piece.
+pub const MAX_ORDER: usize = 14;
+const NIL: usize = usize::MAX;
+
+// Header written into the first frame of every free block.
+// Physical memory is identity mapped, so a frame id is enough to reach it.
+#[repr(C)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+    order: usize,
+}
+
+// Buddy system physical frame allocator.
+// refs. https://en.wikipedia.org/wiki/Buddy_memory_allocation
+pub struct BuddyFrameManager {
+    free_lists: [usize; MAX_ORDER + 1],
+    // bit is set when the frame is the head of a block linked in one of the free lists
+    free_map: [usize; FRAME_COUNTS / BITS_PER_MAP_LINE],
+    total_frames: usize,
+    free_frames: usize,
+    end: usize,
+}
+
+impl BuddyFrameManager {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [NIL; MAX_ORDER + 1],
+            free_map: [0; FRAME_COUNTS / BITS_PER_MAP_LINE],
+            total_frames: 0,
+            free_frames: 0,
+            end: FRAME_MIN,
+        }
+    }
+
+    pub unsafe fn init(&mut self, mm: &MemoryMap) {
+        self.end = frame_end(mm);
+        for_each_range(mm, Usage::Free, |range| {
+            self.add_frames(range.start, range.len())
+        });
+    }
+
+    // Hand frames that have never been allocated over to the manager.
+    pub fn add_frames(&mut self, frame: usize, num_frames: usize) {
+        self.free(frame, num_frames);
+        self.total_frames += num_frames;
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn allocate(&mut self, num_frames: usize) -> Option<usize> {
+        self.allocate_aligned(num_frames, 1)
+    }
+
+    // Blocks of order k always start at a multiple of 2^k frames,
+    // so alignment is satisfied by rounding the order up.
+    pub fn allocate_aligned(&mut self, num_frames: usize, align_frames: usize) -> Option<usize> {
+        debug_assert!(align_frames.is_power_of_two());
+        let order = order_for(num_frames.max(align_frames));
+        if order > MAX_ORDER {
+            return None;
+        }
+        let frame = self.allocate_block(order)?;
+        // return the unused tail of the block
+        let block_frames = 1 << order;
+        if num_frames < block_frames {
+            self.free(frame + num_frames, block_frames - num_frames);
+        }
+        Some(frame)
+    }
+
+    // Free an arbitrary run of frames by splitting it into naturally aligned blocks.
+    pub fn free(&mut self, frame: usize, num_frames: usize) {
+        let mut frame = frame;
+        let end = frame + num_frames;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NIL)?;
+        let frame = self.free_lists[found];
+        self.remove(frame, found);
+
+        // split down to the requested order, giving the upper halves back
+        let mut current = found;
+        while current > order {
+            current -= 1;
+            self.push(frame + (1 << current), current);
+        }
+        Some(frame)
+    }
+
+    fn free_block(&mut self, frame: usize, order: usize) {
+        let mut frame = frame;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy >= self.end
+                || !self.is_free_head(buddy)
+                || unsafe { self.block(buddy).order } != order
+            {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    // `frame` must be the head of a free block, which holds its header.
+    unsafe fn block(&mut self, frame: usize) -> &mut FreeBlock {
+        &mut *((frame * FRAME_BYTES) as *mut FreeBlock)
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            ptr::write(
+                (frame * FRAME_BYTES) as *mut FreeBlock,
+                FreeBlock {
+                    next: head,
+                    prev: NIL,
+                    order,
+                },
+            );
+        }
+        if head != NIL {
+            unsafe { self.block(head).prev = frame };
+        }
+        self.free_lists[order] = frame;
+        self.set_free_head(frame, true);
+        self.free_frames += 1 << order;
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        unsafe {
+            let (next, prev) = {
+                let block = self.block(frame);
+                (block.next, block.prev)
+            };
+            if prev == NIL {
+                self.free_lists[order] = next;
+            } else {
+                self.block(prev).next = next;
+            }
+            if next != NIL {
+                self.block(next).prev = prev;
+            }
+        }
+        self.set_free_head(frame, false);
+        self.free_frames -= 1 << order;
+    }
+
+    fn set_free_head(&mut self, frame: usize, free: bool) {
+        let line_index = frame / BITS_PER_MAP_LINE;
+        let bit_index = frame % BITS_PER_MAP_LINE;
+
+        if free {
+            self.free_map[line_index] |= 1 << bit_index;
+        } else {
+            self.free_map[line_index] &= !(1 << bit_index);
+        }
+    }
+
+    fn is_free_head(&self, frame: usize) -> bool {
+        let line_index = frame / BITS_PER_MAP_LINE;
+        let bit_index = frame % BITS_PER_MAP_LINE;
+
+        (self.free_map[line_index] & 1 << bit_index) != 0
+    }
+}
+
+// smallest order whose block holds `num_frames` frames
+fn order_for(num_frames: usize) -> usize {
+    num_frames.next_power_of_two().trailing_zeros() as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameManager {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(1)
+            .map(|frame| PhysFrame::containing_address(PhysAddr::new((frame * FRAME_BYTES) as u64)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameManager {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free(frame.start_address().as_u64() as usize / FRAME_BYTES, 1);
+    }
+}
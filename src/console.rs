@@ -1,73 +1,506 @@
-use crate::graphics::GRAPHIC;
-use crate::graphics::{Graphic, Rgb};
+use crate::ansi::{self, Action, Parser};
+use crate::font::{self, FONT};
+use crate::graphics::{PixelWriter, Rgb, GRAPHIC};
+use crate::layer::{LayerId, LayerManager, Window, LAYER_MANAGER};
+use crate::BG_COLOR;
+use alloc::{collections::VecDeque, vec, vec::Vec};
 use core::fmt::Write;
+use core::ops::Range;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-const ROWS: usize = 25;
-const COLUMNS: usize = 80;
-const WIDTH_PER_WORD: usize = 8;
-const HEIGHT_PER_WORD: usize = 16;
+// used until the console is attached to the screen
+const DEFAULT_ROWS: usize = 25;
+const DEFAULT_COLUMNS: usize = 80;
+const SCROLLBACK_LINES: usize = 1000;
+const TAB_WIDTH: usize = 8;
+
+const DEFAULT_FG: Rgb = Rgb { r: 0, g: 0, b: 0 };
+
+// The right cell of a full-width character, which is drawn from the cell on its left.
+// NUL is a control character, so it is never printed itself.
+const WIDE_TAIL: char = '\0';
 
 lazy_static! {
     pub static ref CONSOLE: Mutex<Console> = Mutex::new(Console::new());
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Default,
+    // one of the 8 basic colours, made bright by bold text
+    Basic(u8),
+    Rgb(Rgb),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Self = Attributes {
+        fg: Color::Default,
+        bg: Color::Default,
+        bold: false,
+        reverse: false,
+    };
+
+    // (foreground, background) to draw with
+    fn colors(&self) -> (Rgb, Rgb) {
+        let fg = match self.fg {
+            Color::Default => DEFAULT_FG,
+            Color::Basic(index) => ansi::basic_color(index as usize, self.bold),
+            Color::Rgb(rgb) => rgb,
+        };
+        let bg = match self.bg {
+            Color::Default => BG_COLOR,
+            Color::Basic(index) => ansi::basic_color(index as usize, false),
+            Color::Rgb(rgb) => rgb,
+        };
+        if self.reverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    c: char,
+    attributes: Attributes,
+}
+
+impl Cell {
+    const BLANK: Self = Cell {
+        c: ' ',
+        attributes: Attributes::DEFAULT,
+    };
+}
+
+// Text is kept in `screen` and drawn into the console's layer once it exists.
+// Lines scrolled off the top are kept in `scrollback` and can be paged back to.
+// Output is interpreted as a VT100 subset, see `Console::csi`.
 pub struct Console {
-    buffer: [[char; COLUMNS]; ROWS],
+    columns: usize,
+    rows: usize,
+    // glyph size of the font in pixels
+    cell_width: usize,
+    cell_height: usize,
+    screen: VecDeque<Vec<Cell>>,
+    scrollback: VecDeque<Vec<Cell>>,
+    // number of lines the view is scrolled back, 0 shows the live screen
+    view_offset: usize,
     column: usize,
     row: usize,
-    rgb: Rgb,
+    attributes: Attributes,
+    saved_cursor: (usize, usize),
+    parser: Parser,
+    layer: Option<LayerId>,
+}
+
+// Fit the console layer to the current screen resolution and draw what was printed so far.
+// Creates the layer on top of the desktop the first time, and is called again after a
+// mode change.
+pub fn relayout() {
+    let mut console = CONSOLE.lock();
+    let mut layers = LAYER_MANAGER.lock();
+    console.attach(&mut layers);
+    layers.flush();
 }
 
 impl Console {
     pub fn new() -> Self {
         Console {
-            buffer: [[0.into(); COLUMNS]; ROWS],
+            columns: DEFAULT_COLUMNS,
+            rows: DEFAULT_ROWS,
+            cell_width: FONT.width(),
+            cell_height: FONT.height(),
+            screen: (0..DEFAULT_ROWS)
+                .map(|_| vec![Cell::BLANK; DEFAULT_COLUMNS])
+                .collect(),
+            scrollback: VecDeque::new(),
+            view_offset: 0,
             column: 0,
             row: 0,
-            rgb: Rgb { r: 0, g: 0, b: 0 },
+            attributes: Attributes::DEFAULT,
+            saved_cursor: (0, 0),
+            parser: Parser::new(),
+            layer: None,
         }
     }
 
-    pub fn put_string(&mut self, graphic: &mut Graphic, s: &str) {
+    // Resize to the screen and give the layer a window of the new size.
+    fn attach(&mut self, layers: &mut LayerManager) {
+        let (width, height) = match unsafe { GRAPHIC.as_ref() } {
+            Some(graphic) => (graphic.width(), graphic.height()),
+            None => return,
+        };
+        self.resize(
+            (width / self.cell_width).max(1),
+            (height / self.cell_height).max(1),
+        );
+        let mut window = Window::new(
+            self.columns * self.cell_width,
+            self.rows * self.cell_height,
+            BG_COLOR,
+        );
+        self.redraw_all(&mut window);
+        match self.layer {
+            Some(id) => layers.set_window(id, window),
+            None => {
+                let id = layers.new_layer(window, 0, 0);
+                layers.raise_to_top(id);
+                self.layer = Some(id);
+            }
+        }
+    }
+
+    // Re-layout the text for a new geometry. Lines are cut or padded; if the cursor
+    // would fall off the bottom, the top lines move to the scrollback.
+    fn resize(&mut self, columns: usize, rows: usize) {
+        for line in self.screen.iter_mut() {
+            line.resize(columns, Cell::BLANK);
+            // a full-width character cut in half by the new width is dropped
+            if let Some(last) = line.last_mut().filter(|cell| font::is_wide(cell.c)) {
+                last.c = ' ';
+            }
+        }
+        while self.row >= rows {
+            if let Some(line) = self.screen.pop_front() {
+                self.push_scrollback(line);
+            }
+            self.row -= 1;
+        }
+        self.screen.resize(rows, vec![Cell::BLANK; columns]);
+        self.columns = columns;
+        self.rows = rows;
+        self.column = self.column.min(columns);
+        self.saved_cursor = (
+            self.saved_cursor.0.min(columns - 1),
+            self.saved_cursor.1.min(rows - 1),
+        );
+        self.view_offset = 0;
+    }
+
+    fn push_scrollback(&mut self, line: Vec<Cell>) {
+        if self.scrollback.len() == SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(line);
+    }
+
+    // The cell shown at (col, row), taking the scrollback view into account.
+    fn visible_cell(&self, col: usize, row: usize) -> Cell {
+        let line = if row < self.view_offset {
+            &self.scrollback[self.scrollback.len() - self.view_offset + row]
+        } else {
+            &self.screen[row - self.view_offset]
+        };
+        // scrollback lines keep the width they were written with
+        line.get(col).copied().unwrap_or(Cell::BLANK)
+    }
+
+    fn redraw_all(&self, window: &mut Window) {
+        for row in 0..self.rows {
+            for col in 0..self.columns {
+                self.draw_cell(window, col, row);
+            }
+        }
+    }
+
+    // Scroll the view back (positive) or forward (negative) through the scrollback.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let offset = if lines < 0 {
+            self.view_offset.saturating_sub(lines.unsigned_abs())
+        } else {
+            (self.view_offset + lines as usize).min(self.scrollback.len())
+        };
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+        let mut layers = LAYER_MANAGER.lock();
+        if let Some(window) = self.layer.and_then(|id| layers.window(id)) {
+            self.redraw_all(window);
+        }
+        layers.flush();
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn draw_cell(&self, window: &mut Window, col: usize, row: usize) {
+        let cell = self.visible_cell(col, row);
+        if cell.c == WIDE_TAIL && col > 0 {
+            return self.draw_cell(window, col - 1, row);
+        }
+        let width = if font::is_wide(cell.c) { 2 } else { 1 };
+        let (fg, bg) = cell.attributes.colors();
+        // erase whatever was drawn in this cell before
+        window.fill_rect(
+            col * self.cell_width,
+            row * self.cell_height,
+            width * self.cell_width,
+            self.cell_height,
+            bg,
+        );
+        window.write_char(col * self.cell_width, row * self.cell_height, cell.c, fg);
+    }
+
+    pub fn put_string(&mut self, s: &str) {
+        let mut layers = LAYER_MANAGER.lock();
+        let mut window = self.layer.and_then(|id| layers.window(id));
+        // new output brings the view back to the live screen
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            if let Some(window) = window.as_deref_mut() {
+                self.redraw_all(window);
+            }
+        }
         for c in s.chars() {
-            if c == '\n' {
-                self.newline(graphic);
-            } else if self.column < COLUMNS {
-                if self.row < ROWS {
-                    graphic.write_ascii(
-                        self.column * WIDTH_PER_WORD,
-                        self.row * HEIGHT_PER_WORD,
-                        c,
-                        self.rgb,
-                    );
+            match self.parser.advance(c) {
+                Some(Action::Print(c)) => self.print(window.as_deref_mut(), c),
+                Some(Action::Control(c)) => self.control(window.as_deref_mut(), c),
+                Some(Action::Csi { params, final_byte }) => {
+                    self.csi(window.as_deref_mut(), &params, final_byte)
                 }
-                self.buffer[self.row][self.column] = c;
-                self.column += 1;
+                None => {}
             }
         }
+        layers.flush();
     }
 
-    fn newline(&mut self, graphic: &mut Graphic) {
-        self.column = 0;
-        self.row += 1;
-        if self.row >= ROWS {
-            for row in 0..ROWS {
-                graphic.clear_line(row * HEIGHT_PER_WORD, HEIGHT_PER_WORD);
-                for col in 0..COLUMNS {
-                    if row + 1 == ROWS {
-                        self.buffer[row][col] = ' ';
-                    } else {
-                        self.buffer[row][col] = self.buffer[row + 1][col];
-                        graphic.write_ascii(
-                            col * WIDTH_PER_WORD,
-                            row * HEIGHT_PER_WORD,
-                            self.buffer[row + 1][col],
-                            self.rgb,
-                        );
+    // Full-width characters take two cells, and are dropped if only one is left.
+    fn print(&mut self, mut window: Option<&mut Window>, c: char) {
+        let width = if font::is_wide(c) { 2 } else { 1 };
+        if self.column + width > self.columns {
+            return;
+        }
+        let (column, row) = (self.column, self.row);
+        self.split_wide(window.as_deref_mut(), row, column..column + width);
+        let attributes = self.attributes;
+        self.screen[row][column] = Cell { c, attributes };
+        if width == 2 {
+            self.screen[row][column + 1] = Cell {
+                c: WIDE_TAIL,
+                attributes,
+            };
+        }
+        if let Some(window) = window {
+            self.draw_cell(window, column, row);
+        }
+        self.column += width;
+    }
+
+    // Before the cells `line` of `row` are overwritten, blank the other half of any
+    // full-width character they cut through.
+    fn split_wide(&mut self, mut window: Option<&mut Window>, row: usize, line: Range<usize>) {
+        let mut halves = [None, None];
+        if line.start > 0 && self.screen[row][line.start].c == WIDE_TAIL {
+            halves[0] = Some(line.start - 1);
+        }
+        if line.end < self.columns && self.screen[row][line.end].c == WIDE_TAIL {
+            halves[1] = Some(line.end);
+        }
+        for col in halves.into_iter().flatten() {
+            self.screen[row][col].c = ' ';
+            if let Some(window) = window.as_deref_mut() {
+                self.draw_cell(window, col, row);
+            }
+        }
+    }
+
+    fn control(&mut self, window: Option<&mut Window>, c: char) {
+        match c {
+            '\n' => self.newline(window),
+            '\r' => self.column = 0,
+            '\t' => self.column = ((self.column / TAB_WIDTH + 1) * TAB_WIDTH).min(self.columns - 1),
+            '\u{8}' => self.column = self.column.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    // Cursor movement (CUU, CUD, CUF, CUB, CHA, CUP, save/restore), erase (ED, EL) and SGR.
+    fn csi(&mut self, mut window: Option<&mut Window>, params: &[u16], final_byte: char) {
+        // missing or zero counts mean 1
+        let count = |i: usize| params.get(i).copied().unwrap_or(0).max(1) as usize;
+        let mode = params.first().copied().unwrap_or(0);
+        match final_byte {
+            'A' => self.row = self.row.saturating_sub(count(0)),
+            'B' => self.row = (self.row + count(0)).min(self.rows - 1),
+            'C' => self.column = (self.column + count(0)).min(self.columns - 1),
+            'D' => self.column = self.column.min(self.columns - 1).saturating_sub(count(0)),
+            'G' => self.column = (count(0) - 1).min(self.columns - 1),
+            'H' | 'f' => {
+                self.row = (count(0) - 1).min(self.rows - 1);
+                self.column = (count(1) - 1).min(self.columns - 1);
+            }
+            'J' => {
+                let (row, column) = (self.row, self.column);
+                let (rows, line) = match mode {
+                    0 => (row + 1..self.rows, column..self.columns),
+                    1 => (0..row, 0..column + 1),
+                    _ => (0..self.rows, 0..self.columns),
+                };
+                self.erase(window.as_deref_mut(), row, line);
+                for row in rows {
+                    self.erase(window.as_deref_mut(), row, 0..self.columns);
+                }
+            }
+            'K' => {
+                let line = match mode {
+                    0 => self.column..self.columns,
+                    1 => 0..self.column + 1,
+                    _ => 0..self.columns,
+                };
+                self.erase(window, self.row, line);
+            }
+            's' => self.saved_cursor = (self.column, self.row),
+            'u' => (self.column, self.row) = self.saved_cursor,
+            'm' => self.select_graphic_rendition(params),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.attributes = Attributes::DEFAULT;
+        }
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            let attributes = &mut self.attributes;
+            match param {
+                0 => *attributes = Attributes::DEFAULT,
+                1 => attributes.bold = true,
+                22 => attributes.bold = false,
+                7 => attributes.reverse = true,
+                27 => attributes.reverse = false,
+                30..=37 => attributes.fg = Color::Basic((param - 30) as u8),
+                39 => attributes.fg = Color::Default,
+                40..=47 => attributes.bg = Color::Basic((param - 40) as u8),
+                49 => attributes.bg = Color::Default,
+                90..=97 => {
+                    attributes.fg = Color::Rgb(ansi::basic_color((param - 90) as usize, true))
+                }
+                100..=107 => {
+                    attributes.bg = Color::Rgb(ansi::basic_color((param - 100) as usize, true))
+                }
+                38 | 48 => {
+                    // 5;n selects from the 256-colour palette, 2;r;g;b is truecolor
+                    let color = match params.next() {
+                        Some(5) => params.next().map(|n| ansi::palette_256(n as u8)),
+                        Some(2) => match (params.next(), params.next(), params.next()) {
+                            (Some(r), Some(g), Some(b)) => Some(Rgb {
+                                r: r as u8,
+                                g: g as u8,
+                                b: b as u8,
+                            }),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if param == 38 {
+                            attributes.fg = Color::Rgb(color);
+                        } else {
+                            attributes.bg = Color::Rgb(color);
+                        }
                     }
                 }
+                _ => {}
+            }
+        }
+    }
+
+    // Blank the columns `line` of `row` with the current background.
+    fn erase(&mut self, mut window: Option<&mut Window>, row: usize, line: Range<usize>) {
+        let blank = Cell {
+            c: ' ',
+            attributes: self.attributes,
+        };
+        let line = line.start..line.end.min(self.columns);
+        if line.is_empty() {
+            return;
+        }
+        self.split_wide(window.as_deref_mut(), row, line.clone());
+        for col in line {
+            self.screen[row][col] = blank;
+            if let Some(window) = window.as_deref_mut() {
+                self.draw_cell(window, col, row);
+            }
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    // (column, row) the next character is written to
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    pub fn set_cursor(&mut self, column: usize, row: usize) {
+        self.column = column.min(self.columns);
+        self.row = row.min(self.rows - 1);
+    }
+
+    // Underline the cell at the cursor. It is erased when the cell is written again.
+    pub fn draw_cursor(&mut self) {
+        if self.column >= self.columns {
+            return;
+        }
+        let (fg, _) = self.attributes.colors();
+        let mut layers = LAYER_MANAGER.lock();
+        if let Some(window) = self.layer.and_then(|id| layers.window(id)) {
+            window.fill_rect(
+                self.column * self.cell_width,
+                (self.row + 1) * self.cell_height - 2,
+                self.cell_width,
+                2,
+                fg,
+            );
+        }
+        layers.flush();
+    }
+
+    pub fn clear(&mut self) {
+        for line in self.screen.iter_mut() {
+            line.fill(Cell::BLANK);
+        }
+        self.view_offset = 0;
+        self.column = 0;
+        self.row = 0;
+        self.attributes = Attributes::DEFAULT;
+        let mut layers = LAYER_MANAGER.lock();
+        if let Some(window) = self.layer.and_then(|id| layers.window(id)) {
+            window.fill_rect(0, 0, window.width(), window.height(), BG_COLOR);
+        }
+        layers.flush();
+    }
+
+    fn newline(&mut self, window: Option<&mut Window>) {
+        self.column = 0;
+        self.row += 1;
+        if self.row >= self.rows {
+            let blank = Cell {
+                c: ' ',
+                attributes: self.attributes,
+            };
+            if let Some(line) = self.screen.pop_front() {
+                self.push_scrollback(line);
+            }
+            self.screen.push_back(vec![blank; self.columns]);
+            // the window keeps the drawn glyphs, so scrolling is a copy instead of a redraw
+            if let Some(window) = window {
+                let (_, bg) = blank.attributes.colors();
+                window.scroll_up(0, self.rows * self.cell_height, self.cell_height, bg);
             }
             self.row -= 1;
         }
@@ -76,8 +509,7 @@ impl Console {
 
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let graphic = unsafe { GRAPHIC.as_mut().unwrap() };
-        self.put_string(graphic, s);
+        self.put_string(s);
         Ok(())
     }
 }
@@ -0,0 +1,226 @@
+use crate::ascii_font::FONTS;
+use crate::serial_println;
+use alloc::vec::Vec;
+use core::str;
+use lazy_static::lazy_static;
+
+// PC Screen Font version 2
+// https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+const PSF2_MAGIC: u32 = 0x864a_b572;
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+// ends the entries of one glyph in the Unicode table
+const PSF2_SEPARATOR: u8 = 0xff;
+// starts a sequence of code points drawn as one glyph, which we don't support
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+
+// Generated by scripts/make_font.py. Any PSF2 font can be dropped in here.
+//
+// The narrow font covers ASCII, Latin-1, box drawing, block elements and half-width
+// katakana. The wide font is twice as wide and is used for full-width characters,
+// which take two console cells. It has full-width ASCII, katakana and ideographic
+// punctuation; hiragana and kanji are drawn as its fallback box.
+static DEFAULT_FONT: &[u8] = include_bytes!("../fonts/rusmikan.psf");
+static DEFAULT_WIDE_FONT: &[u8] = include_bytes!("../fonts/rusmikan-wide.psf");
+
+lazy_static! {
+    pub static ref FONT: Font = Font::parse(DEFAULT_FONT).unwrap_or_else(|err| {
+        serial_println!("font: {:?}, using the built-in font", err);
+        Font::builtin()
+    });
+    // None if it doesn't fit two cells of FONT, full-width characters then use FONT
+    pub static ref WIDE_FONT: Option<Font> = match Font::parse(DEFAULT_WIDE_FONT) {
+        Ok(font) if font.width() == FONT.width() * 2 && font.height() == FONT.height() => {
+            Some(font)
+        }
+        Ok(_) => {
+            serial_println!("wide font: doesn't match the size of the font");
+            None
+        }
+        Err(err) => {
+            serial_println!("wide font: {:?}", err);
+            None
+        }
+    };
+}
+
+// Whether `c` is East Asian Wide or Fullwidth, and so takes two console cells.
+pub fn is_wide(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x115f // Hangul Jamo initials
+        | 0x2e80..=0x303e // CJK radicals, ideographic punctuation
+        | 0x3041..=0x33ff // kana, CJK symbols
+        | 0x3400..=0x4dbf // CJK extension A
+        | 0x4e00..=0x9fff // CJK unified ideographs
+        | 0xa000..=0xa4cf // Yi
+        | 0xac00..=0xd7a3 // Hangul syllables
+        | 0xf900..=0xfaff // CJK compatibility ideographs
+        | 0xfe30..=0xfe4f // CJK compatibility forms
+        | 0xff00..=0xff60 // full-width ASCII
+        | 0xffe0..=0xffe6 // full-width signs
+        | 0x20000..=0x3fffd // CJK extensions B and later
+    )
+}
+
+// The glyph `c` is drawn with, twice the cell width for full-width characters.
+pub fn glyph(c: char) -> Glyph<'static> {
+    match WIDE_FONT.as_ref() {
+        Some(wide) if is_wide(c) => wide.glyph(c),
+        _ => FONT.glyph(c),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    TooShort,
+    BadMagic,
+    BadHeader,
+}
+
+pub struct Font {
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    // bytes per glyph, may include padding after the bitmap
+    glyph_size: usize,
+    glyphs: &'static [u8],
+    // (code point, glyph index), sorted for binary search
+    unicode: Vec<(char, u32)>,
+    // index drawn for characters the font doesn't have
+    fallback: u32,
+}
+
+// One glyph bitmap, rows of `bytes_per_row` bytes with the leftmost pixel in the top bit.
+pub struct Glyph<'a> {
+    bitmap: &'a [u8],
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+}
+
+impl<'a> Glyph<'a> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        self.bitmap[y * self.bytes_per_row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+impl Font {
+    pub fn parse(data: &'static [u8]) -> Result<Self, FontError> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(FontError::TooShort);
+        }
+        let field = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        if field(0) != PSF2_MAGIC {
+            return Err(FontError::BadMagic);
+        }
+        let header_size = field(2) as usize;
+        let flags = field(3);
+        let length = field(4) as usize;
+        let glyph_size = field(5) as usize;
+        let height = field(6) as usize;
+        let width = field(7) as usize;
+
+        let bytes_per_row = (width + 7) / 8;
+        if length == 0 || width == 0 || height == 0 || glyph_size < bytes_per_row * height {
+            return Err(FontError::BadHeader);
+        }
+        let glyphs_end = header_size + length * glyph_size;
+        if data.len() < glyphs_end {
+            return Err(FontError::TooShort);
+        }
+
+        let mut font = Font {
+            width,
+            height,
+            bytes_per_row,
+            glyph_size,
+            glyphs: &data[header_size..glyphs_end],
+            unicode: Vec::new(),
+            fallback: 0,
+        };
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            font.parse_unicode_table(&data[glyphs_end..], length);
+        } else {
+            // without a table, glyph i is code point i
+            font.unicode = (0..length.min(0x110000) as u32)
+                .filter_map(|i| char::from_u32(i).map(|c| (c, i)))
+                .collect();
+        }
+        font.unicode.sort_unstable_by_key(|&(c, _)| c);
+        font.fallback = font
+            .index('\u{fffd}')
+            .or_else(|| font.index('?'))
+            .unwrap_or(0);
+        Ok(font)
+    }
+
+    // Each glyph has a run of UTF-8 encoded characters, optionally followed by
+    // sequences, terminated by 0xff.
+    fn parse_unicode_table(&mut self, table: &[u8], length: usize) {
+        let entries = table.split(|&b| b == PSF2_SEPARATOR).take(length);
+        for (index, entry) in entries.enumerate() {
+            let singles = entry
+                .split(|&b| b == PSF2_START_SEQUENCE)
+                .next()
+                .unwrap_or(&[]);
+            // a broken entry only loses the characters of that glyph
+            if let Ok(chars) = str::from_utf8(singles) {
+                self.unicode
+                    .extend(chars.chars().map(|c| (c, index as u32)));
+            }
+        }
+    }
+
+    // The 8x16 table in ascii_font: ASCII and JIS X 0201 half-width katakana.
+    pub fn builtin() -> Self {
+        let mut unicode: Vec<(char, u32)> = (0x20..0x7f).map(|i| (i as u8 as char, i)).collect();
+        unicode
+            .extend((0xa1..0xe0).filter_map(|i| char::from_u32(0xff61 + i - 0xa1).map(|c| (c, i))));
+        Font {
+            width: 8,
+            height: 16,
+            bytes_per_row: 1,
+            glyph_size: 16,
+            glyphs: FONTS.as_flattened(),
+            unicode,
+            fallback: '?' as u32,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn index(&self, c: char) -> Option<u32> {
+        self.unicode
+            .binary_search_by_key(&c, |&(c, _)| c)
+            .ok()
+            .map(|i| self.unicode[i].1)
+    }
+
+    pub fn glyph(&self, c: char) -> Glyph<'_> {
+        let mut index = self.index(c).unwrap_or(self.fallback) as usize;
+        if (index + 1) * self.glyph_size > self.glyphs.len() {
+            index = 0;
+        }
+        let start = index * self.glyph_size;
+        Glyph {
+            bitmap: &self.glyphs[start..start + self.bytes_per_row * self.height],
+            width: self.width,
+            height: self.height,
+            bytes_per_row: self.bytes_per_row,
+        }
+    }
+}
@@ -1,47 +1,213 @@
 use core::mem;
+use core::ops::Range;
 use rusmikan::MemoryMap;
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use spin::Mutex;
+#[cfg(feature = "loader-memory-types")]
+use uefi::table::boot::MemoryType;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 use x86_64::PhysAddr;
 
+use crate::acpi;
+use crate::paging::IDENTITY_MAP_END;
 use crate::serial_println;
+use crate::smp::trampoline_addr;
 
-const MAX_PHYSICAL_MEMORY_BYTES: usize = 128 * 1024 * 1024 * 1024;
+// Frames are reached through the identity map (the buddy system even writes its free
+// lists into them), so memory above it is never handed out.
+const MAX_PHYSICAL_MEMORY_BYTES: usize = IDENTITY_MAP_END as usize;
 pub const FRAME_BYTES: usize = 4096;
-const FRAME_COUNTS: usize = MAX_PHYSICAL_MEMORY_BYTES / FRAME_BYTES;
-const BITS_PER_MAP_LINE: usize = 8 * mem::size_of::<usize>();
-const FRAME_MIN: usize = 1; // FIXME: 0 causes alloc error
+pub const FRAME_COUNTS: usize = MAX_PHYSICAL_MEMORY_BYTES / FRAME_BYTES;
+pub const BITS_PER_MAP_LINE: usize = 8 * mem::size_of::<usize>();
+pub const FRAME_MIN: usize = 1; // FIXME: 0 causes alloc error
 
-pub static mut BITMAP_FRAME_MANAGER: BitMapFrameManager = BitMapFrameManager::new();
+// The physical frame manager backend is chosen at build time.
+// The bitmap manager is the default, `--features buddy-frame-manager` selects the buddy system.
+#[cfg(not(feature = "buddy-frame-manager"))]
+pub type FrameManager = BitMapFrameManager;
+#[cfg(feature = "buddy-frame-manager")]
+pub type FrameManager = crate::buddy::BuddyFrameManager;
+
+// Page tables, AP stacks and large heap objects all come from here, so every user goes
+// through the lock. Nothing may allocate from the heap while holding it: the heap takes
+// ALLOCATOR first and then this lock.
+pub static FRAME_MANAGER: Mutex<FrameManager> = Mutex::new(FrameManager::new());
+
+// Kernel is linked at --image-base in x86_64-rusmikan.json and `_end` is provided by the linker.
+const KERNEL_BASE: usize = 0x100000;
+extern "C" {
+    static _end: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    // free from the start
+    Free,
+    // still in use while booting, free once `reclaim_boot_memory` is called
+    AfterBoot,
+    Never,
+}
+
+// Only memory the firmware no longer uses after ExitBootServices can be handed out.
+// Boot services memory may still hold the firmware page tables and what the loader passed
+// us, and ACPI_RECLAIM holds the ACPI tables, so both wait until boot is done.
+// Loader data (kernel image, memory map, boot stack) and runtime services stay untouched.
+#[cfg(feature = "loader-memory-types")]
+fn usage(ty: MemoryType) -> Usage {
+    match ty {
+        MemoryType::CONVENTIONAL => Usage::Free,
+        MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::BOOT_SERVICES_DATA
+        | MemoryType::ACPI_RECLAIM => Usage::AfterBoot,
+        _ => Usage::Never,
+    }
+}
+
+// Physical address range and usage of every memory descriptor.
+fn regions(mm: &MemoryMap) -> impl Iterator<Item = (Usage, Range<usize>)> + '_ {
+    mm.descriptors().iter().map(|d| {
+        #[cfg(feature = "loader-memory-types")]
+        let usage = usage(d.ty);
+        // A loader that gives no memory types is expected to pass free memory only.
+        #[cfg(not(feature = "loader-memory-types"))]
+        let usage = Usage::Free;
+        (usage, d.phys_start as usize..d.phys_end as usize)
+    })
+}
+
+// Frames the kernel itself occupies, whatever the memory map says.
+// The GOP framebuffer is MMIO and never shows up as usable memory.
+fn reserved() -> [Range<usize>; 2] {
+    [
+        frame_range(KERNEL_BASE, unsafe { &_end as *const u8 as usize }),
+        frame_range(trampoline_addr(), trampoline_addr() + FRAME_BYTES),
+    ]
+}
+
+// Whether the memory map lists all of `range` as free from the start.
+// Reserved ranges such as the trampoline page count as free here.
+pub fn is_free(mm: &MemoryMap, range: Range<usize>) -> bool {
+    regions(mm)
+        .any(|(usage, r)| usage == Usage::Free && r.start <= range.start && range.end <= r.end)
+}
+
+pub unsafe fn init(mm: &MemoryMap) {
+    FRAME_MANAGER.lock().init(mm);
+}
+
+// Release boot services and ACPI_RECLAIM memory, except the ACPI tables themselves.
+// Call this once paging has switched to our own page tables, ACPI parsing is done
+// and the memory map is no longer used.
+pub unsafe fn reclaim_boot_memory(mm: &MemoryMap) {
+    let mut reserved = reserved().to_vec();
+    reserved.extend(acpi::table_ranges().map(|range| frame_range(range.start, range.end)));
+    let mut manager = FRAME_MANAGER.lock();
+    for_each_range_except(mm, Usage::AfterBoot, &reserved, |range| {
+        manager.add_frames(range.start, range.len())
+    });
+}
+
+// frame ids covering the physical address range [start, end)
+fn frame_range(start: usize, end: usize) -> Range<usize> {
+    start / FRAME_BYTES..(end + FRAME_BYTES - 1) / FRAME_BYTES
+}
+
+// One past the last frame the manager may ever own, including memory reclaimed later.
+pub fn frame_end(mm: &MemoryMap) -> usize {
+    regions(mm)
+        .filter(|(usage, _)| *usage != Usage::Never)
+        .map(|(_, range)| range.end / FRAME_BYTES)
+        .max()
+        .unwrap_or(FRAME_MIN)
+        .min(FRAME_COUNTS)
+}
+
+// Call `f` with every run of frames of the given usage, leaving out the reserved ranges.
+pub fn for_each_range(mm: &MemoryMap, usage: Usage, f: impl FnMut(Range<usize>)) {
+    for_each_range_except(mm, usage, &reserved(), f);
+}
+
+fn for_each_range_except(
+    mm: &MemoryMap,
+    usage: Usage,
+    reserved: &[Range<usize>],
+    mut f: impl FnMut(Range<usize>),
+) {
+    for (_, range) in regions(mm).filter(|(u, _)| *u == usage) {
+        // partial frames at the edges of a descriptor are not usable
+        let start = ((range.start + FRAME_BYTES - 1) / FRAME_BYTES).max(FRAME_MIN);
+        let end = (range.end / FRAME_BYTES).min(FRAME_COUNTS);
+        if start < end {
+            subtract_reserved(start..end, reserved, &mut f);
+        }
+    }
+}
+
+fn subtract_reserved(
+    range: Range<usize>,
+    reserved: &[Range<usize>],
+    f: &mut impl FnMut(Range<usize>),
+) {
+    let (first, rest) = match reserved.split_first() {
+        Some(split) => split,
+        None => {
+            f(range);
+            return;
+        }
+    };
+    let left = range.start..range.end.min(first.start);
+    let right = range.start.max(first.end)..range.end;
+    for piece in [left, right] {
+        if !piece.is_empty() {
+            subtract_reserved(piece, rest, f);
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct BitMapFrameManager {
     alloc_map: [usize; FRAME_COUNTS / BITS_PER_MAP_LINE],
     begin: usize,
     end: usize,
+    total_frames: usize,
+    free_frames: usize,
 }
 
 impl BitMapFrameManager {
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             alloc_map: [0; FRAME_COUNTS / BITS_PER_MAP_LINE],
             begin: FRAME_MIN,
             end: FRAME_COUNTS,
+            total_frames: 0,
+            free_frames: 0,
         }
     }
 
-    pub unsafe fn init(mm: &MemoryMap) {
-        let mut available_end: usize = FRAME_MIN;
-        for d in mm.descriptors() {
-            let phys_start = d.phys_start as usize;
-            let phys_end = d.phys_end as usize;
-            if available_end < phys_start {
-                let frame_id = available_end / FRAME_BYTES;
-                let frame_num = (phys_start - available_end) / FRAME_BYTES;
-                BITMAP_FRAME_MANAGER.mark_allocated(frame_id, frame_num);
-            }
-            available_end = phys_end;
+    // Every frame starts out allocated and only the available ranges are released.
+    pub unsafe fn init(&mut self, mm: &MemoryMap) {
+        self.end = frame_end(mm);
+        for line in
+            self.alloc_map[..(self.end + BITS_PER_MAP_LINE - 1) / BITS_PER_MAP_LINE].iter_mut()
+        {
+            *line = !0;
         }
-        BITMAP_FRAME_MANAGER.end = available_end / FRAME_BYTES;
+        for_each_range(mm, Usage::Free, |range| {
+            self.add_frames(range.start, range.len())
+        });
+    }
+
+    // Hand frames that have never been allocated over to the manager.
+    pub fn add_frames(&mut self, frame: usize, num_frames: usize) {
+        self.free(frame, num_frames);
+        self.total_frames += num_frames;
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
     }
 
     fn mark_allocated(&mut self, start_frame_id: usize, frame_num: usize) {
@@ -49,6 +215,7 @@ impl BitMapFrameManager {
             let index = start_frame_id + i;
             self.set_bit(index, true);
         }
+        self.free_frames -= frame_num;
     }
 
     fn set_bit(&mut self, index: usize, allocated: bool) {
@@ -70,11 +237,18 @@ impl BitMapFrameManager {
     }
 
     pub fn allocate(&mut self, num_frames: usize) -> Option<usize> {
-        let mut frame = self.begin;
+        self.allocate_aligned(num_frames, 1)
+    }
+
+    // Allocate `num_frames` contiguous frames whose first frame id is a multiple of
+    // `align_frames` (must be a power of two).
+    pub fn allocate_aligned(&mut self, num_frames: usize, align_frames: usize) -> Option<usize> {
+        debug_assert!(align_frames.is_power_of_two());
+        let mut frame = align_up(self.begin, align_frames);
         loop {
             let mut i: usize = 0;
             while i < num_frames {
-                if frame + i > self.end {
+                if frame + i >= self.end {
                     return None;
                 }
                 if self.get_bit(frame + i) {
@@ -86,7 +260,7 @@ impl BitMapFrameManager {
                 self.mark_allocated(frame, num_frames);
                 return Some(frame);
             }
-            frame += i + 1;
+            frame = align_up(frame + i + 1, align_frames);
         }
     }
 
@@ -94,14 +268,23 @@ impl BitMapFrameManager {
         for i in 0..num_frames {
             self.set_bit(frame + i, false);
         }
+        self.free_frames += num_frames;
+    }
+}
+
+pub fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitMapFrameManager {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(1)
+            .map(|frame| PhysFrame::containing_address(PhysAddr::new((frame * FRAME_BYTES) as u64)))
     }
 }
 
-//unsafe impl FrameAllocator<Size4KiB> for BitMapFrameManager {
-//    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-//        match self.allocate(1) {
-//            Some(frame) => Some(PhysFrame::from_start_address(PhysAddr::new(frame as u64)).unwrap()),
-//            None => None,
-//        }
-//    }
-//}
+impl FrameDeallocator<Size4KiB> for BitMapFrameManager {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free(frame.start_address().as_u64() as usize / FRAME_BYTES, 1);
+    }
+}
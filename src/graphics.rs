@@ -1,79 +1,474 @@
-use crate::ascii_font::FONTS;
-use crate::BG_COLOR;
-use rusmikan::{FrameBuffer, FrameBufferConfig};
+use crate::font;
+use crate::serial_println;
+use alloc::{vec, vec::Vec};
+use core::ptr;
+use rusmikan::{FrameBuffer, FrameBufferConfig, PixelFormat};
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Rgb {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
 
-pub static mut GRAPHIC: Option<Graphic> = None;
-
-pub struct Graphic {
-    fb_config: FrameBufferConfig,
-    pixel_writer: unsafe fn(&mut FrameBuffer, usize, Rgb),
+// Screen or buffer area. May lie partially outside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
 }
 
-impl Graphic {
-    pub unsafe fn init(fb_config: FrameBufferConfig) -> &'static mut Self {
-        GRAPHIC = Some(Self::new(fb_config));
-        GRAPHIC.as_mut().unwrap()
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
     }
 
-    pub fn new(fb_config: FrameBufferConfig) -> Self {
-        unsafe fn write_pixel_rgb(fb: &mut FrameBuffer, base: usize, rgb: Rgb) {
-            fb.write_value(base, [rgb.r, rgb.g, rgb.b]);
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        Rect::new(x, y, (right - x).max(0), (bottom - y).max(0))
+    }
+
+    // Smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
         }
-        unsafe fn write_pixel_bgr(fb: &mut FrameBuffer, base: usize, rgb: Rgb) {
-            fb.write_value(base, [rgb.b, rgb.g, rgb.r]);
+        if other.is_empty() {
+            return *self;
         }
-        let pixel_writer = match fb_config.pixel_format {
-            rusmikan::PixelFormat::RGB => write_pixel_rgb,
-            rusmikan::PixelFormat::BGR => write_pixel_bgr,
-        };
-        Graphic {
-            fb_config,
-            pixel_writer,
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32) -> Rect {
+        Rect::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+}
+
+// Anything pixels can be drawn onto: the framebuffer itself or an off-screen window.
+pub trait PixelWriter {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn write(&mut self, x: usize, y: usize, rgb: Rgb);
+
+    // Draw the glyph of `c` from the console fonts. Only set pixels are written.
+    fn write_char(&mut self, x: usize, y: usize, c: char, rgb: Rgb) {
+        let glyph = font::glyph(c);
+        for dy in 0..glyph.height() {
+            for dx in 0..glyph.width() {
+                if glyph.is_set(dx, dy) {
+                    self.write(x + dx, y + dy, rgb);
+                }
+            }
         }
     }
 
-    pub fn write(&mut self, x: usize, y: usize, rgb: Rgb) {
-        let pixels_per_scan_line = self.fb_config.pixels_per_scan_line;
-        let fb = &mut self.fb_config.frame_buffer;
-        unsafe {
-            (self.pixel_writer)(fb, (x + pixels_per_scan_line * y) * 4, rgb);
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: Rgb) {
+        for dy in y..y + height {
+            for dx in x..x + width {
+                self.write(dx, dy, rgb);
+            }
         }
     }
+}
 
-    pub fn write_ascii(&mut self, x: usize, y: usize, c: char, rgb: Rgb) {
-        if (c as u32) > 0x7f {
-            return;
+// Pixels kept in RAM, row after row without padding.
+// Block operations clip to the buffer and return the area they changed.
+pub struct PixelBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+}
+
+impl PixelBuffer {
+    pub fn new(width: usize, height: usize, fill: Rgb) -> Self {
+        PixelBuffer {
+            width,
+            height,
+            pixels: vec![fill; width * height],
         }
-        let font: [u8; 16] = FONTS[c as usize];
-        for dy in 0..16 {
-            for dx in 0..8 {
-                if (font[dy] << dx & 0x80) != 0 {
-                    self.write(x + dx, y + dy, rgb);
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width as i32, self.height as i32)
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+        self.pixels[y * self.width + x]
+    }
+
+    // Columns [x, x + width) of row `y`.
+    pub fn row(&self, x: usize, y: usize, width: usize) -> &[Rgb] {
+        let start = y * self.width + x;
+        &self.pixels[start..start + width]
+    }
+
+    pub fn fill(&mut self, rect: Rect, rgb: Rgb) -> Rect {
+        let rect = rect.intersection(&self.bounds());
+        for y in rect.y..rect.y + rect.height {
+            let start = y as usize * self.width + rect.x as usize;
+            self.pixels[start..start + rect.width as usize].fill(rgb);
+        }
+        rect
+    }
+
+    // Clip a copy of `area` in a buffer with bounds `src_bounds` to (x, y) in this one.
+    // Returns the source and destination areas.
+    fn clip_copy(&self, src_bounds: Rect, area: Rect, x: i32, y: i32) -> (Rect, Rect) {
+        let (dx, dy) = (x - area.x, y - area.y);
+        let src = area.intersection(&src_bounds);
+        let dst = src.offset(dx, dy).intersection(&self.bounds());
+        (dst.offset(-dx, -dy), dst)
+    }
+
+    // Copy `area` of this buffer to (x, y). The areas may overlap, which makes this a scroll.
+    pub fn copy_rect(&mut self, area: Rect, x: i32, y: i32) -> Rect {
+        let (src, dst) = self.clip_copy(self.bounds(), area, x, y);
+        if dst.is_empty() {
+            return dst;
+        }
+        let width = dst.width as usize;
+        let mut copy_row = |row: i32| {
+            let from = (src.y + row) as usize * self.width + src.x as usize;
+            let to = (dst.y + row) as usize * self.width + dst.x as usize;
+            self.pixels.copy_within(from..from + width, to);
+        };
+        // do not overwrite rows that are yet to be copied
+        if dst.y > src.y {
+            (0..dst.height).rev().for_each(&mut copy_row);
+        } else {
+            (0..dst.height).for_each(&mut copy_row);
+        }
+        dst
+    }
+
+    // Copy `area` of `src` to (x, y), skipping pixels equal to `transparent`.
+    pub fn blit(
+        &mut self,
+        x: i32,
+        y: i32,
+        src: &PixelBuffer,
+        area: Rect,
+        transparent: Option<Rgb>,
+    ) -> Rect {
+        let (from, dst) = self.clip_copy(src.bounds(), area, x, y);
+        let width = dst.width as usize;
+        for row in 0..dst.height {
+            let line = src.row(from.x as usize, (from.y + row) as usize, width);
+            let start = (dst.y + row) as usize * self.width + dst.x as usize;
+            let target = &mut self.pixels[start..start + width];
+            match transparent {
+                None => target.copy_from_slice(line),
+                Some(key) => {
+                    for (pixel, &rgb) in target.iter_mut().zip(line) {
+                        if rgb != key {
+                            *pixel = rgb;
+                        }
+                    }
                 }
             }
         }
+        dst
+    }
+}
+
+impl PixelWriter for PixelBuffer {
+    fn width(&self) -> usize {
+        self.width
     }
 
-    pub fn clear(&mut self) {
-        let vert = self.fb_config.vertical_resolution;
+    fn height(&self) -> usize {
+        self.height
+    }
 
-        self.clear_line(0, vert);
+    fn write(&mut self, x: usize, y: usize, rgb: Rgb) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = rgb;
+        }
     }
 
-    pub fn clear_line(&mut self, y: usize, height: usize) {
-        let hori = self.fb_config.horizontal_resolution;
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: Rgb) {
+        self.fill(
+            Rect::new(x as i32, y as i32, width as i32, height as i32),
+            rgb,
+        );
+    }
+}
 
-        for dy in y..y + height {
-            for x in 0..hori {
-                self.write(x, dy, BG_COLOR);
+// Position and width of one colour channel inside a pixel value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Channel {
+    shift: u32,
+    bits: u32,
+}
+
+impl Channel {
+    fn from_mask(mask: u32) -> Self {
+        if mask == 0 {
+            return Channel { shift: 0, bits: 0 };
+        }
+        Channel {
+            shift: mask.trailing_zeros(),
+            bits: mask.count_ones(),
+        }
+    }
+
+    fn encode(&self, value: u8) -> u32 {
+        let value = value as u32;
+        let scaled = if self.bits >= 8 {
+            value << (self.bits - 8)
+        } else {
+            value >> (8 - self.bits)
+        };
+        scaled << self.shift
+    }
+}
+
+// How a colour is stored in the framebuffer: channel masks and pixel size.
+// Covers the two 32-bit UEFI layouts and arbitrary PixelBitMask modes of 8 to 32 bpp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelLayout {
+    bytes_per_pixel: usize,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl PixelLayout {
+    // None if the firmware gives no linear framebuffer (BltOnly).
+    pub fn new(format: PixelFormat) -> Option<Self> {
+        match format {
+            PixelFormat::RGB => Some(Self::from_masks(0xff, 0xff00, 0xff_0000, 0xff00_0000)),
+            PixelFormat::BGR => Some(Self::from_masks(0xff_0000, 0xff00, 0xff, 0xff00_0000)),
+            #[cfg(feature = "loader-gop-mode")]
+            PixelFormat::Bitmask {
+                red,
+                green,
+                blue,
+                reserved,
+            } => Some(Self::from_masks(red, green, blue, reserved)),
+            #[cfg(feature = "loader-gop-mode")]
+            PixelFormat::BltOnly => None,
+        }
+    }
+
+    pub fn from_masks(red: u32, green: u32, blue: u32, reserved: u32) -> Self {
+        // the highest bit used by any channel decides the pixel size
+        let bits = 32 - (red | green | blue | reserved).leading_zeros() as usize;
+        PixelLayout {
+            bytes_per_pixel: ((bits + 7) / 8).max(1),
+            red: Channel::from_mask(red),
+            green: Channel::from_mask(green),
+            blue: Channel::from_mask(blue),
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bytes_per_pixel
+    }
+
+    fn encode(&self, rgb: Rgb) -> u32 {
+        self.red.encode(rgb.r) | self.green.encode(rgb.g) | self.blue.encode(rgb.b)
+    }
+}
+
+pub static mut GRAPHIC: Option<Graphic> = None;
+
+// Where `flush` writes the converted rows to.
+enum Target {
+    // linear framebuffer at a known address
+    Linear(*mut u8),
+    // The loader's framebuffer when its address is not passed, written through
+    // FrameBuffer::write_value 3 bytes a pixel. Only RGB and BGR (4 bytes a pixel) occur then.
+    #[cfg_attr(feature = "loader-gop-mode", allow(dead_code))]
+    Loader(FrameBuffer),
+}
+
+// Everything is drawn into a shadow buffer in RAM. `flush` converts the changed part of
+// each row to the framebuffer's pixel format and writes it out, so the slow framebuffer
+// memory is only written, never read, and only where something changed.
+pub struct Graphic {
+    target: Target,
+    width: usize,
+    height: usize,
+    pixels_per_scan_line: usize,
+    layout: PixelLayout,
+    shadow: PixelBuffer,
+    // per row, the columns [start, end) changed since the last flush
+    dirty: Vec<(usize, usize)>,
+    // one row converted to the framebuffer format
+    row_buffer: Vec<u8>,
+}
+
+impl Graphic {
+    // Without a usable framebuffer GRAPHIC stays None and output only goes to serial.
+    pub unsafe fn init(fb_config: FrameBufferConfig) {
+        GRAPHIC = Self::new(fb_config);
+        if GRAPHIC.is_none() {
+            serial_println!("no linear framebuffer, console output is disabled");
+        }
+    }
+
+    pub fn new(fb_config: FrameBufferConfig) -> Option<Self> {
+        let layout = PixelLayout::new(fb_config.pixel_format)?;
+        #[cfg(feature = "loader-gop-mode")]
+        let target = Target::Linear(fb_config.frame_buffer.base());
+        #[cfg(not(feature = "loader-gop-mode"))]
+        let target = Target::Loader(fb_config.frame_buffer);
+        Some(Self::with_target(
+            target,
+            fb_config.horizontal_resolution,
+            fb_config.vertical_resolution,
+            fb_config.pixels_per_scan_line,
+            layout,
+        ))
+    }
+
+    // For a framebuffer set up by a driver rather than the loader.
+    // `base` must stay mapped for `pixels_per_scan_line * height` pixels.
+    pub fn with_framebuffer(
+        base: *mut u8,
+        width: usize,
+        height: usize,
+        pixels_per_scan_line: usize,
+        layout: PixelLayout,
+    ) -> Self {
+        Self::with_target(
+            Target::Linear(base),
+            width,
+            height,
+            pixels_per_scan_line,
+            layout,
+        )
+    }
+
+    fn with_target(
+        target: Target,
+        width: usize,
+        height: usize,
+        pixels_per_scan_line: usize,
+        layout: PixelLayout,
+    ) -> Self {
+        Graphic {
+            target,
+            width,
+            height,
+            pixels_per_scan_line,
+            layout,
+            shadow: PixelBuffer::new(width, height, Rgb { r: 0, g: 0, b: 0 }),
+            dirty: vec![(0, 0); height],
+            row_buffer: Vec::with_capacity(width * layout.bytes_per_pixel()),
+        }
+    }
+
+    pub fn horizontal_resolution(&self) -> usize {
+        self.width
+    }
+
+    pub fn vertical_resolution(&self) -> usize {
+        self.height
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        let (start, end) = (rect.x as usize, (rect.x + rect.width) as usize);
+        for y in rect.y..rect.y + rect.height {
+            let span = &mut self.dirty[y as usize];
+            *span = if span.0 == span.1 {
+                (start, end)
+            } else {
+                (span.0.min(start), span.1.max(end))
+            };
+        }
+    }
+
+    pub fn fill(&mut self, rect: Rect, rgb: Rgb) {
+        let changed = self.shadow.fill(rect, rgb);
+        self.mark_dirty(changed);
+    }
+
+    pub fn copy_rect(&mut self, area: Rect, x: i32, y: i32) {
+        let changed = self.shadow.copy_rect(area, x, y);
+        self.mark_dirty(changed);
+    }
+
+    pub fn blit(
+        &mut self,
+        x: i32,
+        y: i32,
+        src: &PixelBuffer,
+        area: Rect,
+        transparent: Option<Rgb>,
+    ) {
+        let changed = self.shadow.blit(x, y, src, area, transparent);
+        self.mark_dirty(changed);
+    }
+
+    // Write the changed parts of the shadow buffer to the framebuffer,
+    // converting a row at a time and copying it out in one go.
+    pub fn flush(&mut self) {
+        let pixels_per_scan_line = self.pixels_per_scan_line;
+        let bytes_per_pixel = self.layout.bytes_per_pixel();
+        for (y, span) in self.dirty.iter_mut().enumerate() {
+            let (start, end) = core::mem::replace(span, (0, 0));
+            if start >= end {
+                continue;
+            }
+            self.row_buffer.clear();
+            for &rgb in self.shadow.row(start, y, end - start) {
+                let value = self.layout.encode(rgb).to_le_bytes();
+                self.row_buffer.extend_from_slice(&value[..bytes_per_pixel]);
+            }
+            let offset = (y * pixels_per_scan_line + start) * bytes_per_pixel;
+            match &mut self.target {
+                Target::Linear(base) => unsafe {
+                    let dst = base.add(offset);
+                    ptr::copy_nonoverlapping(self.row_buffer.as_ptr(), dst, self.row_buffer.len());
+                },
+                Target::Loader(fb) => {
+                    for (i, pixel) in self.row_buffer.chunks(bytes_per_pixel).enumerate() {
+                        let value = [pixel[0], pixel[1], pixel[2]];
+                        unsafe { fb.write_value(offset + i * bytes_per_pixel, value) };
+                    }
+                }
             }
         }
     }
 }
+
+impl PixelWriter for Graphic {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn write(&mut self, x: usize, y: usize, rgb: Rgb) {
+        self.fill(Rect::new(x as i32, y as i32, 1, 1), rgb);
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: Rgb) {
+        self.fill(
+            Rect::new(x as i32, y as i32, width as i32, height as i32),
+            rgb,
+        );
+    }
+}
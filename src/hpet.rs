@@ -0,0 +1,279 @@
+use crate::acpi::{self, Polarity, TriggerMode};
+use crate::interrupts::{IRQ_HPET, IRQ_OFFSET};
+use crate::ioapic::{disable_gsi, enable_gsi, has_gsi};
+use crate::lapic::lapic_id;
+use crate::paging::map_mmio;
+use crate::serial_println;
+use bit_field::BitField;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// High Precision Event Timer
+// refs. IA-PC HPET (High Precision Event Timers) Specification 1.0a
+//       https://wiki.osdev.org/HPET
+const REGISTER_BLOCK_SIZE: u64 = 0x400;
+
+// register offsets
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const fn timer_config(n: usize) -> usize {
+    0x100 + 0x20 * n
+}
+const fn timer_comparator(n: usize) -> usize {
+    0x108 + 0x20 * n
+}
+
+// General Configuration Register
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+
+// Timer N Configuration and Capability Register
+const TN_INT_TYPE_CNF: u64 = 1 << 1; // level triggered
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3; // periodic
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+const TN_32MODE_CNF: u64 = 1 << 8;
+const TN_FSB_EN_CNF: u64 = 1 << 14;
+
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+
+// Comparators we have interrupt vectors for, starting at IRQ_HPET.
+// The specification guarantees at least 3.
+pub const MAX_TIMERS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotPresent,
+    NoSuchTimer,
+    PeriodicUnsupported,
+    // none of the I/O APIC inputs the comparator can drive exists
+    NoRoute,
+}
+
+struct Hpet {
+    base: *mut u8,
+    // main counter tick in femtoseconds
+    period_fs: u64,
+    num_timers: usize,
+    counter_is_64_bit: bool,
+    min_tick: u64,
+}
+
+static mut HPET: Option<Hpet> = None;
+static mut HANDLERS: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+// GSI each comparator is routed to, to mask it again
+static mut ROUTES: [Option<u32>; MAX_TIMERS] = [None; MAX_TIMERS];
+
+// Last reading of a 32-bit main counter, extended to 64 bits in software.
+static COUNTER_HIGH: AtomicU64 = AtomicU64::new(0);
+
+impl Hpet {
+    unsafe fn read(&self, offset: usize) -> u64 {
+        ptr::read_volatile(self.base.add(offset) as *const u64)
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        ptr::write_volatile(self.base.add(offset) as *mut u64, value);
+    }
+
+    unsafe fn counter(&self) -> u64 {
+        if self.counter_is_64_bit {
+            return self.read(MAIN_COUNTER);
+        }
+        // Keep the last reading; a smaller one means the counter wrapped since.
+        // Readings must come at least once per wrap, about 5 minutes at 14.3 MHz.
+        // The counter is read again after every failed exchange: a reading older than the
+        // one another CPU has just stored would otherwise look like a wrap.
+        let mut last = COUNTER_HIGH.load(Ordering::Acquire);
+        loop {
+            let low = self.read(MAIN_COUNTER) & 0xffff_ffff;
+            let mut next = (last & !0xffff_ffff) | low;
+            if low < last & 0xffff_ffff {
+                next += 1 << 32;
+            }
+            match COUNTER_HIGH.compare_exchange_weak(
+                last,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND) as u64
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * FEMTOSECONDS_PER_NANOSECOND / self.period_fs as u128) as u64
+    }
+}
+
+// Map the register block found in the ACPI HPET table and start the main counter.
+pub unsafe fn init() {
+    let info = match acpi::hpet() {
+        Some(info) => info,
+        None => {
+            serial_println!("HPET is not found");
+            return;
+        }
+    };
+    let base = match map_mmio(info.address, REGISTER_BLOCK_SIZE) {
+        Ok(base) => base,
+        Err(err) => {
+            serial_println!("failed to map HPET: {:?}", err);
+            return;
+        }
+    };
+    let mut hpet = Hpet {
+        base: base.as_mut_ptr(),
+        period_fs: 0,
+        num_timers: 0,
+        counter_is_64_bit: false,
+        min_tick: info.min_tick as u64,
+    };
+    let capabilities = hpet.read(GENERAL_CAPABILITIES);
+    hpet.period_fs = capabilities.get_bits(32..64);
+    hpet.num_timers = capabilities.get_bits(8..13) as usize + 1;
+    hpet.counter_is_64_bit = capabilities.get_bit(13);
+    // the specification limits the period to 100 ns
+    if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+        serial_println!("HPET: invalid period {} fs", hpet.period_fs);
+        return;
+    }
+
+    // Stop and reset the counter, mask every comparator and use I/O APIC routing.
+    let config = hpet.read(GENERAL_CONFIG) & !(ENABLE_CNF | LEG_RT_CNF);
+    hpet.write(GENERAL_CONFIG, config);
+    hpet.write(MAIN_COUNTER, 0);
+    for n in 0..hpet.num_timers {
+        let timer = hpet.read(timer_config(n));
+        hpet.write(
+            timer_config(n),
+            timer & !(TN_INT_ENB_CNF | TN_TYPE_CNF | TN_FSB_EN_CNF),
+        );
+    }
+    hpet.write(GENERAL_CONFIG, config | ENABLE_CNF);
+
+    serial_println!(
+        "HPET: {} kHz, {} comparators, {}-bit counter",
+        1_000_000_000_000 / hpet.period_fs,
+        hpet.num_timers,
+        if hpet.counter_is_64_bit { 64 } else { 32 }
+    );
+    HPET = Some(hpet);
+}
+
+pub fn is_available() -> bool {
+    unsafe { HPET.is_some() }
+}
+
+// Main counter frequency in Hz.
+pub fn frequency() -> Option<u64> {
+    unsafe { HPET.as_ref() }.map(|hpet| 1_000_000_000_000_000 / hpet.period_fs)
+}
+
+// Main counter ticks since `init`.
+pub fn ticks() -> Option<u64> {
+    unsafe { HPET.as_ref().map(|hpet| hpet.counter()) }
+}
+
+// Monotonic nanoseconds since `init`.
+pub fn nanoseconds() -> Option<u64> {
+    unsafe { HPET.as_ref().map(|hpet| hpet.ticks_to_ns(hpet.counter())) }
+}
+
+// Call `handler` from interrupt context after `ns` nanoseconds, once or every `ns`.
+// The comparator is routed through the I/O APIC to the vector IRQ_HPET + `timer`.
+pub unsafe fn start_timer(
+    timer: usize,
+    mode: TimerMode,
+    ns: u64,
+    handler: fn(),
+) -> Result<(), HpetError> {
+    let hpet = HPET.as_ref().ok_or(HpetError::NotPresent)?;
+    if timer >= hpet.num_timers.min(MAX_TIMERS) {
+        return Err(HpetError::NoSuchTimer);
+    }
+    stop_timer(timer);
+
+    let mut config = hpet.read(timer_config(timer));
+    if mode == TimerMode::Periodic && config & TN_PER_INT_CAP == 0 {
+        return Err(HpetError::PeriodicUnsupported);
+    }
+    // Prefer the inputs above the ISA range, which nothing else uses.
+    let routes = config.get_bits(32..64) as u32;
+    let gsi = (16..32)
+        .chain(0..16)
+        .find(|&gsi| routes.get_bit(gsi as usize) && has_gsi(gsi))
+        .ok_or(HpetError::NoRoute)?;
+
+    HANDLERS[timer] = Some(handler);
+    ROUTES[timer] = Some(gsi);
+    let vector = IRQ_OFFSET + (IRQ_HPET as usize + timer) as u8;
+    enable_gsi(
+        gsi,
+        vector,
+        Polarity::ActiveHigh,
+        TriggerMode::Edge,
+        lapic_id(),
+    );
+
+    let mut ticks = hpet.ns_to_ticks(ns).max(1);
+    if mode == TimerMode::Periodic {
+        ticks = ticks.max(hpet.min_tick);
+    }
+    config.set_bits(9..14, gsi as u64);
+    config &= !(TN_INT_TYPE_CNF | TN_TYPE_CNF | TN_32MODE_CNF | TN_FSB_EN_CNF);
+    config |= TN_INT_ENB_CNF;
+    match mode {
+        TimerMode::OneShot => {
+            hpet.write(timer_config(timer), config);
+            hpet.write(timer_comparator(timer), hpet.counter() + ticks);
+        }
+        TimerMode::Periodic => {
+            // with VAL_SET the first write sets the comparator, the second the period
+            hpet.write(timer_config(timer), config | TN_TYPE_CNF | TN_VAL_SET_CNF);
+            hpet.write(timer_comparator(timer), hpet.counter() + ticks);
+            hpet.write(timer_comparator(timer), ticks);
+        }
+    }
+    Ok(())
+}
+
+pub unsafe fn stop_timer(timer: usize) {
+    let hpet = match HPET.as_ref() {
+        Some(hpet) => hpet,
+        None => return,
+    };
+    if timer >= hpet.num_timers.min(MAX_TIMERS) {
+        return;
+    }
+    let config = hpet.read(timer_config(timer));
+    hpet.write(
+        timer_config(timer),
+        config & !(TN_INT_ENB_CNF | TN_TYPE_CNF),
+    );
+    if let Some(gsi) = ROUTES[timer].take() {
+        disable_gsi(gsi);
+    }
+    HANDLERS[timer] = None;
+}
+
+// Called by the interrupt handler of comparator `timer`.
+pub fn handle_interrupt(timer: usize) {
+    if let Some(handler) = unsafe { HANDLERS[timer] } {
+        handler();
+    }
+}
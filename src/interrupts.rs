@@ -1,35 +1,88 @@
+use crate::backtrace::capture_exception;
 use crate::ioapic::init_io_apic;
-use crate::lapic::{disable_pic_8259, init_lapic, EOI};
-use crate::{print, println, serial_println, JIFFIES};
-use x86_64::instructions::port::Port;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use crate::keyboard::{self, Layout};
+use crate::lapic::{disable_pic_8259, end_of_interrupt, init_ap_lapic, init_lapic};
+use crate::panic_screen::{self, Registers};
+use crate::segment::DOUBLE_FAULT_IST_INDEX;
+use crate::smp;
+use crate::{hpet, mouse, serial_println, task, timer, JIFFIES};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 // IRQ
 pub const IRQ_OFFSET: u8 = 32; // first 32 entries are reserved for exception by CPU
 pub const IRQ_TMR: u32 = 0;
 pub const IRQ_KBD: u32 = 1;
+pub const IRQ_MOUSE: u32 = 12;
+// HPET comparators 0..hpet::MAX_TIMERS, routed above the ISA IRQs
+pub const IRQ_HPET: u32 = 16;
 
 pub unsafe fn init() {
     init_idt();
     disable_pic_8259();
     init_lapic();
+    keyboard::init(Layout::Us);
+    mouse::init();
     init_io_apic();
     x86_64::instructions::interrupts::enable();
 }
 
+// Application processors share the IDT and only need their Local APIC enabled.
+pub unsafe fn init_ap() {
+    IDT.load();
+    init_ap_lapic();
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 enum InterruptIndex {
     Timer = IRQ_OFFSET,
     Keyboard,
+    Mouse = IRQ_OFFSET + IRQ_MOUSE as u8,
+    Hpet0 = IRQ_OFFSET + IRQ_HPET as u8,
+    Hpet1,
+    Hpet2,
 }
 
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
 unsafe fn init_idt() {
+    IDT.divide_error.set_handler_fn(divide_error_handler);
+    IDT.debug.set_handler_fn(debug_handler);
+    IDT.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
     IDT.breakpoint.set_handler_fn(breakpoint_handler);
+    IDT.overflow.set_handler_fn(overflow_handler);
+    IDT.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    IDT.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    IDT.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    IDT.double_fault
+        .set_handler_fn(double_fault_handler)
+        .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    IDT.invalid_tss.set_handler_fn(invalid_tss_handler);
+    IDT.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    IDT.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    IDT.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    IDT.page_fault.set_handler_fn(page_fault_handler);
+    IDT.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    IDT.alignment_check.set_handler_fn(alignment_check_handler);
+    IDT.machine_check.set_handler_fn(machine_check_handler);
+    IDT.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    IDT.virtualization.set_handler_fn(virtualization_handler);
+    IDT.security_exception
+        .set_handler_fn(security_exception_handler);
     IDT[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
     IDT[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+    IDT[InterruptIndex::Mouse as usize].set_handler_fn(mouse_interrupt_handler);
+    IDT[InterruptIndex::Hpet0 as usize].set_handler_fn(hpet0_interrupt_handler);
+    IDT[InterruptIndex::Hpet1 as usize].set_handler_fn(hpet1_interrupt_handler);
+    IDT[InterruptIndex::Hpet2 as usize].set_handler_fn(hpet2_interrupt_handler);
     IDT.load();
 }
 
@@ -37,30 +90,129 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+// Report an unrecoverable CPU exception on serial and the panic screen.
+// Never inlined so that the backtrace can find the handler's frame right above this one.
+#[inline(never)]
+fn fatal_exception(
+    name: &str,
+    vector: u8,
+    error_code: Option<u64>,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    let backtrace = unsafe { capture_exception(stack_frame.instruction_pointer.as_u64()) };
+    let registers = Registers::from_stack_frame(stack_frame);
+    panic_screen::die(
+        "EXCEPTION",
+        &|w| {
+            writeln!(w, "{} (vector {})", name, vector)?;
+            if let Some(error_code) = error_code {
+                writeln!(w, "Error Code: {:#x}", error_code)?;
+            }
+            Ok(())
+        },
+        &registers,
+        &backtrace,
+    )
+}
 
-    let mut kb = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-    let mut port = Port::new(0x60);
+macro_rules! exception_handler {
+    ($handler:ident, $name:expr, $vector:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            fatal_exception($name, $vector, None, &stack_frame);
+        }
+    };
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(event)) = kb.add_byte(scancode) {
-        if let Some(key) = kb.process_keyevent(event) {
-            if let DecodedKey::Unicode(character) = key {
-                print!("{}", character);
-            }
+macro_rules! exception_handler_with_error_code {
+    ($handler:ident, $name:expr, $vector:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fatal_exception($name, $vector, Some(error_code), &stack_frame);
         }
+    };
+}
+
+exception_handler!(divide_error_handler, "DIVIDE ERROR", 0);
+exception_handler!(debug_handler, "DEBUG", 1);
+exception_handler!(non_maskable_interrupt_handler, "NON MASKABLE INTERRUPT", 2);
+exception_handler!(overflow_handler, "OVERFLOW", 4);
+exception_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED", 5);
+exception_handler!(invalid_opcode_handler, "INVALID OPCODE", 6);
+exception_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE", 7);
+exception_handler_with_error_code!(invalid_tss_handler, "INVALID TSS", 10);
+exception_handler_with_error_code!(segment_not_present_handler, "SEGMENT NOT PRESENT", 11);
+exception_handler_with_error_code!(stack_segment_fault_handler, "STACK SEGMENT FAULT", 12);
+exception_handler_with_error_code!(
+    general_protection_fault_handler,
+    "GENERAL PROTECTION FAULT",
+    13
+);
+exception_handler!(x87_floating_point_handler, "x87 FLOATING POINT", 16);
+exception_handler_with_error_code!(alignment_check_handler, "ALIGNMENT CHECK", 17);
+exception_handler!(simd_floating_point_handler, "SIMD FLOATING POINT", 19);
+exception_handler!(virtualization_handler, "VIRTUALIZATION", 20);
+exception_handler_with_error_code!(security_exception_handler, "SECURITY EXCEPTION", 30);
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    fatal_exception("DOUBLE FAULT", 8, Some(error_code), &stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    fatal_exception("PAGE FAULT", 14, Some(error_code.bits()), &stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fatal_exception("MACHINE CHECK", 18, None, &stack_frame);
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    keyboard::handle_interrupt();
+
+    unsafe {
+        end_of_interrupt();
     }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    mouse::handle_interrupt();
 
     unsafe {
-        *(EOI as *mut u32) = 0;
+        end_of_interrupt();
     }
 }
 
+macro_rules! hpet_interrupt_handler {
+    ($handler:ident, $timer:expr) => {
+        extern "x86-interrupt" fn $handler(_stack_frame: InterruptStackFrame) {
+            hpet::handle_interrupt($timer);
+
+            unsafe {
+                end_of_interrupt();
+            }
+        }
+    };
+}
+
+hpet_interrupt_handler!(hpet0_interrupt_handler, 0);
+hpet_interrupt_handler!(hpet1_interrupt_handler, 1);
+hpet_interrupt_handler!(hpet2_interrupt_handler, 2);
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     unsafe {
-        JIFFIES += 1; // 1 tick
-        println!("Timer Interrupt: {} tick", JIFFIES);
-        *(EOI as *mut u32) = 0;
+        // a timer fires on the CPU that armed it, but only the BSP counts jiffies and runs tasks
+        let is_bsp = smp::is_bsp();
+        if is_bsp {
+            JIFFIES += 1;
+        }
+        timer::handle_interrupt();
+        end_of_interrupt();
+        if is_bsp {
+            task::schedule();
+        }
     }
 }
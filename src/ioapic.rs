@@ -1,18 +1,29 @@
 use crate::{
-    interrupts::{IRQ_KBD, IRQ_OFFSET},
-    lapic::LAPIC,
+    acpi::{madt, Polarity, TriggerMode, MAX_IO_APICS},
+    interrupts::{IRQ_KBD, IRQ_MOUSE, IRQ_OFFSET},
+    lapic::lapic_id,
+    paging::map_mmio,
+    serial_println,
 };
+use arrayvec::ArrayVec;
 use core::ptr;
 
 struct IoApic {
     ptr: *mut IoApicMmio,
+    gsi_base: u32,
+    max_intr: u32,
 }
 
 impl IoApic {
-    fn new(addr: u32) -> Self {
-        Self {
+    fn new(addr: u64, gsi_base: u32) -> Self {
+        let mut ioapic = Self {
             ptr: addr as *mut IoApicMmio,
-        }
+            gsi_base,
+            max_intr: 0,
+        };
+        // Maximum Redirection Entry holds the number of entries minus one
+        ioapic.max_intr = unsafe { (ioapic.read(IOAPICVER) >> 16 & 0xFF) + 1 };
+        ioapic
     }
 
     unsafe fn read(&self, reg: u32) -> u32 {
@@ -24,6 +35,10 @@ impl IoApic {
         ptr::write_volatile(&mut (*self.ptr).reg, reg);
         ptr::write_volatile(&mut (*self.ptr).data, data);
     }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.max_intr
+    }
 }
 
 // https://wiki.osdev.org/APIC#IO_APIC_Configuration
@@ -34,30 +49,108 @@ struct IoApicMmio {
     data: u32, // IOAPICBASE + 0x10
 }
 
-// MMIO Address
-const IOAPIC: u32 = 0xFEC00000;
 // IOREGSEL Offset
-const IOAPICID: u32 = 0x00000000;
+const IOAPICVER: u32 = 0x00000001;
 const IOREDTBL: u32 = 0x00000010;
 
+const REDTBL_DELIVERY_NMI: u32 = 0x00000400;
 const REDTBL_MASKED: u32 = 0x00010000;
+const REDTBL_ACTIVE_LOW: u32 = 0x00002000;
+const REDTBL_LEVEL: u32 = 0x00008000;
+
+static mut IO_APICS: ArrayVec<IoApic, MAX_IO_APICS> = ArrayVec::new_const();
 
 pub unsafe fn init_io_apic() {
-    let ioapic = IoApic::new(IOAPIC);
-    let max_intr = ioapic.read(IOAPICID) >> 16 & 0xFF;
+    for entry in madt().io_apics.iter() {
+        let base = map_mmio(entry.address as u64, 0x1000).expect("failed to map I/O APIC");
+        let ioapic = IoApic::new(base.as_u64(), entry.gsi_base);
 
-    // Mark all interrupts edge-triggered, active high, disable, and not routed to any CPUs.
-    for i in 0..max_intr {
-        ioapic.write(IOREDTBL + 2 * i, REDTBL_MASKED | (IRQ_OFFSET as u32 + i));
-        ioapic.write(IOREDTBL + 2 * i + 1, 0);
+        // Mark all interrupts edge-triggered, active high, disable, and not routed to any CPUs.
+        for i in 0..ioapic.max_intr {
+            ioapic.write(IOREDTBL + 2 * i, REDTBL_MASKED | (IRQ_OFFSET as u32 + i));
+            ioapic.write(IOREDTBL + 2 * i + 1, 0);
+        }
+        if IO_APICS.try_push(ioapic).is_err() {
+            break;
+        }
     }
 
-    // FIXME: no supported SMP
-    // current implementation get lapic_id from processor that the code is currently executing
-    // on (BSP)
-    let lapic_id = *(LAPIC as *mut u32) >> 24; // Get Local APIC ID
+    // External interrupts are delivered to the processor running this code (BSP)
+    let lapic_id = lapic_id(); // Get Local APIC ID
+
+    // Route IRQ1 and IRQ12 to the given cpunum, following any interrupt source override.
+    enable_irq(IRQ_KBD as u8, IRQ_OFFSET + IRQ_KBD as u8, lapic_id);
+    enable_irq(IRQ_MOUSE as u8, IRQ_OFFSET + IRQ_MOUSE as u8, lapic_id);
+
+    // Deliver the NMI sources listed in the MADT to the BSP as NMIs.
+    for nmi in madt().nmi_sources.iter() {
+        enable_nmi(nmi.gsi, nmi.flags.polarity(), lapic_id);
+    }
+}
+
+// Unmask ISA `irq` and deliver it as `vector` to the Local APIC `lapic_id`.
+pub unsafe fn enable_irq(irq: u8, vector: u8, lapic_id: u32) {
+    let (gsi, flags) = madt().irq_to_gsi(irq);
+    enable_gsi(
+        gsi,
+        vector,
+        flags.polarity(),
+        flags.trigger_mode(),
+        lapic_id,
+    );
+}
+
+// Unmask `gsi` and deliver it as `vector` to the Local APIC `lapic_id`.
+pub unsafe fn enable_gsi(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+    lapic_id: u32,
+) {
+    let mut low = vector as u32;
+    if polarity == Polarity::ActiveLow {
+        low |= REDTBL_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        low |= REDTBL_LEVEL;
+    }
+    set_redirection(gsi, low, lapic_id);
+}
+
+// Unmask `gsi` and deliver it as an NMI to the Local APIC `lapic_id`.
+// NMI delivery must be edge triggered, and the vector is ignored.
+pub unsafe fn enable_nmi(gsi: u32, polarity: Polarity, lapic_id: u32) {
+    let mut low = REDTBL_DELIVERY_NMI;
+    if polarity == Polarity::ActiveLow {
+        low |= REDTBL_ACTIVE_LOW;
+    }
+    set_redirection(gsi, low, lapic_id);
+}
+
+unsafe fn set_redirection(gsi: u32, low: u32, lapic_id: u32) {
+    let ioapic = match IO_APICS.iter().find(|ioapic| ioapic.handles(gsi)) {
+        Some(ioapic) => ioapic,
+        None => {
+            serial_println!("I/O APIC: no input for GSI {}", gsi);
+            return;
+        }
+    };
+    let index = gsi - ioapic.gsi_base;
+    ioapic.write(IOREDTBL + 2 * index, low);
+    ioapic.write(IOREDTBL + 2 * index + 1, lapic_id << 24);
+}
+
+// Mask `gsi` again.
+pub unsafe fn disable_gsi(gsi: u32) {
+    if let Some(ioapic) = IO_APICS.iter().find(|ioapic| ioapic.handles(gsi)) {
+        let index = gsi - ioapic.gsi_base;
+        let low = ioapic.read(IOREDTBL + 2 * index);
+        ioapic.write(IOREDTBL + 2 * index, low | REDTBL_MASKED);
+    }
+}
 
-    // Mark IRQ1 interrupt edge-triggered, active high, enable, and routed to the given cpunum
-    ioapic.write(IOREDTBL + 2 * IRQ_KBD, IRQ_OFFSET as u32 + IRQ_KBD);
-    ioapic.write(IOREDTBL + 2 * IRQ_KBD + 1, lapic_id << 24);
+// Whether some I/O APIC has an input for `gsi`.
+pub fn has_gsi(gsi: u32) -> bool {
+    unsafe { IO_APICS.iter().any(|ioapic| ioapic.handles(gsi)) }
 }
@@ -0,0 +1,244 @@
+use crate::ps2;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet2,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// keyboard commands
+const KBD_SET_LEDS: u8 = 0xed;
+const KBD_SCANCODE_SET: u8 = 0xf0;
+const KBD_ENABLE_SCANNING: u8 = 0xf4;
+
+const LED_SCROLL_LOCK: u8 = 0x01;
+const LED_NUM_LOCK: u8 = 0x02;
+const LED_CAPS_LOCK: u8 = 0x04;
+
+const KEY_QUEUE_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Jp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyInput {
+    pub key: DecodedKey,
+    // modifier state when the key was pressed
+    pub modifiers: Modifiers,
+}
+
+const EMPTY_INPUT: KeyInput = KeyInput {
+    key: DecodedKey::Unicode('\0'),
+    modifiers: Modifiers {
+        shift: false,
+        ctrl: false,
+        alt: false,
+        caps_lock: false,
+        num_lock: false,
+        scroll_lock: false,
+    },
+};
+
+// Single producer (keyboard interrupt) / single consumer ring buffer.
+// It never blocks, so the interrupt handler can't deadlock against a reader.
+struct KeyQueue {
+    buffer: [UnsafeCell<KeyInput>; KEY_QUEUE_SIZE],
+    head: AtomicUsize, // next slot to read
+    tail: AtomicUsize, // next slot to write
+}
+
+unsafe impl Sync for KeyQueue {}
+
+impl KeyQueue {
+    const fn new() -> Self {
+        const SLOT: UnsafeCell<KeyInput> = UnsafeCell::new(EMPTY_INPUT);
+        KeyQueue {
+            buffer: [SLOT; KEY_QUEUE_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // Returns false and drops the input if the queue is full.
+    fn push(&self, input: KeyInput) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % KEY_QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { *self.buffer[tail].get() = input };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<KeyInput> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let input = unsafe { *self.buffer[head].get() };
+        self.head
+            .store((head + 1) % KEY_QUEUE_SIZE, Ordering::Release);
+        Some(input)
+    }
+}
+
+enum Decoder {
+    Us(Keyboard<layouts::Us104Key, ScancodeSet2>),
+    Jp(Keyboard<layouts::Jis109Key, ScancodeSet2>),
+}
+
+impl Decoder {
+    fn new(layout: Layout) -> Self {
+        match layout {
+            Layout::Us => Decoder::Us(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet2,
+                HandleControl::Ignore,
+            )),
+            Layout::Jp => Decoder::Jp(Keyboard::new(
+                layouts::Jis109Key,
+                ScancodeSet2,
+                HandleControl::Ignore,
+            )),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        let event = match self {
+            Decoder::Us(kb) => kb.add_byte(byte),
+            Decoder::Jp(kb) => kb.add_byte(byte),
+        };
+        event.ok().flatten()
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Decoder::Us(kb) => kb.process_keyevent(event),
+            Decoder::Jp(kb) => kb.process_keyevent(event),
+        }
+    }
+}
+
+struct KeyboardState {
+    decoder: Decoder,
+    modifiers: Modifiers,
+}
+
+impl KeyboardState {
+    // Track modifiers ourselves: they are reported with every key and drive the LEDs.
+    fn update_modifiers(&mut self, event: &KeyEvent) {
+        let down = event.state == KeyState::Down;
+        let m = &mut self.modifiers;
+        match event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => m.shift = down,
+            KeyCode::ControlLeft | KeyCode::ControlRight => m.ctrl = down,
+            KeyCode::AltLeft | KeyCode::AltRight => m.alt = down,
+            KeyCode::CapsLock if down => m.caps_lock = !m.caps_lock,
+            KeyCode::NumpadLock if down => m.num_lock = !m.num_lock,
+            KeyCode::ScrollLock if down => m.scroll_lock = !m.scroll_lock,
+            _ => {}
+        }
+    }
+
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.modifiers.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.modifiers.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.modifiers.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+}
+
+static KEY_QUEUE: KeyQueue = KeyQueue::new();
+static KEYBOARD: Mutex<Option<KeyboardState>> = Mutex::new(None);
+// LED byte waiting for the keyboard to ACK the Set LEDs command
+static LED_PENDING: AtomicBool = AtomicBool::new(false);
+static LEDS: AtomicU8 = AtomicU8::new(0);
+
+// Switch the controller to untranslated scancode set 2. Called with interrupts disabled.
+pub unsafe fn init(layout: Layout) {
+    let config = ps2::read_config();
+    ps2::write_config((config | ps2::CONFIG_KBD_INTERRUPT) & !ps2::CONFIG_TRANSLATION);
+    ps2::send_keyboard(KBD_SCANCODE_SET);
+    ps2::send_keyboard(2);
+    ps2::send_keyboard(KBD_ENABLE_SCANNING);
+    ps2::send_keyboard(KBD_SET_LEDS);
+    ps2::send_keyboard(0);
+
+    *KEYBOARD.lock() = Some(KeyboardState {
+        decoder: Decoder::new(layout),
+        modifiers: Modifiers::default(),
+    });
+}
+
+pub fn set_layout(layout: Layout) {
+    interrupts::without_interrupts(|| {
+        if let Some(state) = KEYBOARD.lock().as_mut() {
+            state.decoder = Decoder::new(layout);
+        }
+    });
+}
+
+// Next key pressed, if any. Safe to call from tasks; there must be a single reader.
+pub fn read_key() -> Option<KeyInput> {
+    KEY_QUEUE.pop()
+}
+
+// Called from the keyboard interrupt handler.
+pub fn handle_interrupt() {
+    let byte = unsafe { ps2::read_data() };
+
+    if byte == ps2::ACK || byte == ps2::RESEND {
+        if byte == ps2::ACK && LED_PENDING.swap(false, Ordering::SeqCst) {
+            unsafe { ps2::write_data(LEDS.load(Ordering::SeqCst)) };
+        }
+        return;
+    }
+
+    let mut keyboard = KEYBOARD.lock();
+    let state = match keyboard.as_mut() {
+        Some(state) => state,
+        None => return,
+    };
+    let event = match state.decoder.add_byte(byte) {
+        Some(event) => event,
+        None => return,
+    };
+
+    let leds = state.leds();
+    state.update_modifiers(&event);
+    if state.leds() != leds {
+        // the LED byte follows once the keyboard ACKs the command
+        LEDS.store(state.leds(), Ordering::SeqCst);
+        LED_PENDING.store(true, Ordering::SeqCst);
+        unsafe { ps2::write_data(KBD_SET_LEDS) };
+    }
+
+    if let Some(key) = state.decoder.process_keyevent(event) {
+        KEY_QUEUE.push(KeyInput {
+            key,
+            modifiers: state.modifiers,
+        });
+    }
+}
@@ -1,30 +1,59 @@
-use crate::acpi::wait_milliseconds_with_pm_timer;
+use crate::acpi::{madt, wait_milliseconds_with_pm_timer, Polarity, TriggerMode};
+use crate::paging::map_mmio;
+use crate::time;
+use core::arch::x86_64::__cpuid;
 use core::ptr;
 use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
 
 use crate::interrupts::{IRQ_OFFSET, IRQ_TMR};
 
-// MMIO Address
-pub const LAPIC: u32 = 0xFEE00000;
-// LAPIC Register Address
-const SVR: u32 = LAPIC + 0x000000F0;
-pub const EOI: u32 = LAPIC + 0x000000B0;
-const LVT_TMR: u32 = LAPIC + 0x00000320;
-const TMRINITCNT: u32 = LAPIC + 0x00000380;
-const TMRCURRCNT: u32 = LAPIC + 0x00000390;
-const TMRDIV: u32 = LAPIC + 0x000003e0;
+// LAPIC Register Offset
+const ID: u64 = 0x00000020;
+const SVR: u64 = 0x000000F0;
+const EOI: u64 = 0x000000B0;
+const LVT_TMR: u64 = 0x00000320;
+const LVT_LINT0: u64 = 0x00000350;
+const LVT_LINT1: u64 = 0x00000360;
+const ICR_LOW: u64 = 0x00000300;
+const ICR_HIGH: u64 = 0x00000310;
+const TMRINITCNT: u64 = 0x00000380;
+const TMRCURRCNT: u64 = 0x00000390;
+const TMRDIV: u64 = 0x000003e0;
 
 const SVR_ENABLED: u32 = 0x00000100;
 const X1: u32 = 0b1011; // divided by 1 (Divide Configuration Register)
 const LVT_MASKED: u32 = 0x00010000;
 const LVT_ONESHOT: u32 = 0x00000000;
-const LVT_PERIODIC: u32 = 0x00020000;
+const LVT_TSC_DEADLINE: u32 = 0x00040000;
+const LVT_NMI: u32 = 0x00000400;
+const LVT_ACTIVE_LOW: u32 = 0x00002000;
+const LVT_LEVEL: u32 = 0x00008000;
+const ICR_DELIVERY_PENDING: u32 = 0x00001000;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+const CPUID_TSC_DEADLINE: u32 = 1 << 24; // ECX of leaf 1
 
 static mut LAPIC_TMR_FREQ: u32 = 0;
+// the timer fires when the TSC reaches IA32_TSC_DEADLINE instead of counting down
+static mut TSC_DEADLINE_MODE: bool = false;
+// virtual address LAPIC registers are mapped at
+static mut LAPIC_BASE: u64 = 0;
+
+unsafe fn read(reg: u64) -> u32 {
+    ptr::read_volatile((LAPIC_BASE + reg) as *const u32)
+}
+
+unsafe fn write(reg: u64, value: u32) {
+    ptr::write_volatile((LAPIC_BASE + reg) as *mut u32, value);
+}
 
 pub unsafe fn init_lapic() {
-    let svr = SVR as *mut u32;
-    *svr = SVR_ENABLED | 0xFF;
+    LAPIC_BASE = map_mmio(madt().local_apic_address, 0x1000)
+        .expect("failed to map Local APIC")
+        .as_u64();
+    write(SVR, SVR_ENABLED | 0xFF);
+    init_lapic_nmi();
 
     init_lapic_timer();
 }
@@ -34,12 +63,45 @@ pub unsafe fn disable_pic_8259() {
     Port::new(0x21).write(0xffu8);
 }
 
+// Configure LINT0/LINT1 as NMI inputs as described by the MADT
+unsafe fn init_lapic_nmi() {
+    let apic_id = lapic_id();
+    let processor_id = madt()
+        .processors
+        .iter()
+        .find(|p| p.apic_id as u32 == apic_id)
+        .map(|p| p.processor_id);
+
+    for nmi in madt().local_apic_nmis.iter() {
+        if nmi.processor_id != 0xff && Some(nmi.processor_id) != processor_id {
+            continue;
+        }
+        let mut lvt = LVT_NMI;
+        if nmi.flags.polarity() == Polarity::ActiveLow {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        if nmi.flags.trigger_mode() == TriggerMode::Level {
+            lvt |= LVT_LEVEL;
+        }
+        match nmi.lint {
+            0 => write(LVT_LINT0, lvt),
+            1 => write(LVT_LINT1, lvt),
+            _ => {}
+        }
+    }
+}
+
+pub unsafe fn lapic_id() -> u32 {
+    read(ID) >> 24
+}
+
+pub unsafe fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
 unsafe fn init_lapic_timer() {
-    let lvt_timer = LVT_TMR as *mut u32;
-    let timer_div = TMRDIV as *mut u32;
-    let timer_init_cnt = TMRINITCNT as *mut u32;
-    *timer_div = X1;
-    *lvt_timer = LVT_ONESHOT | LVT_MASKED;
+    write(TMRDIV, X1);
+    write(LVT_TMR, LVT_ONESHOT | LVT_MASKED);
 
     start_lapic_timer();
     wait_milliseconds_with_pm_timer(100);
@@ -47,21 +109,75 @@ unsafe fn init_lapic_timer() {
     stop_lapic_timer();
     LAPIC_TMR_FREQ = elapsed * 10;
 
-    *lvt_timer = LVT_PERIODIC | (IRQ_OFFSET as u32 + IRQ_TMR);
-    ptr::write_volatile(timer_init_cnt, LAPIC_TMR_FREQ / 100);
+    // TSC deadlines are only as good as the TSC, so require it to be the clock source
+    TSC_DEADLINE_MODE = __cpuid(1).ecx & CPUID_TSC_DEADLINE != 0 && time::ns_to_tsc(0).is_some();
+    set_timer_mode();
+}
+
+// Unmask the timer in the mode chosen by `init_lapic_timer`.
+// Nothing fires until `set_timer_deadline`.
+unsafe fn set_timer_mode() {
+    write(TMRDIV, X1);
+    let mode = if TSC_DEADLINE_MODE {
+        LVT_TSC_DEADLINE
+    } else {
+        LVT_ONESHOT
+    };
+    write(LVT_TMR, mode | (IRQ_OFFSET as u32 + IRQ_TMR));
+}
+
+// Program the timer interrupt for `ktime_ns` reaching `deadline`, or stop it with `None`.
+// A countdown too long for the LAPIC fires early; the caller then programs it again.
+pub unsafe fn set_timer_deadline(deadline: Option<u64>) {
+    if LAPIC_BASE == 0 {
+        return;
+    }
+    if TSC_DEADLINE_MODE {
+        // writing 0 disarms the timer, a deadline in the past fires at once
+        let tsc = match deadline {
+            Some(deadline) => time::ns_to_tsc(deadline).unwrap_or(0).max(1),
+            None => 0,
+        };
+        Msr::new(IA32_TSC_DEADLINE).write(tsc);
+        return;
+    }
+    let count = match deadline {
+        Some(deadline) => {
+            let ns = deadline.saturating_sub(time::ktime_ns());
+            let count = ns as u128 * LAPIC_TMR_FREQ as u128 / 1_000_000_000;
+            count.clamp(1, u32::MAX as u128) as u32
+        }
+        None => 0,
+    };
+    write(TMRINITCNT, count);
+}
+
+// Enable the Local APIC of an application processor.
+// Registers are at the same address on every CPU. The timer is set up like the BSP's,
+// with the frequency calibrated there: all Local APIC timers run off the same clock.
+pub unsafe fn init_ap_lapic() {
+    write(SVR, SVR_ENABLED | 0xFF);
+    init_lapic_nmi();
+    set_timer_mode();
+}
+
+// Send an Inter-Processor Interrupt and wait until it has been accepted.
+pub unsafe fn send_ipi(apic_id: u32, command: u32) {
+    write(ICR_HIGH, apic_id << 24);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
 }
 
 pub unsafe fn start_lapic_timer() {
-    let timer_init_cnt = TMRINITCNT as *mut u32;
-    ptr::write_volatile(timer_init_cnt, u32::MAX);
+    write(TMRINITCNT, u32::MAX);
 }
 
 pub unsafe fn stop_lapic_timer() {
-    let timer_init_cnt = TMRINITCNT as *mut u32;
-    ptr::write_volatile(timer_init_cnt, 0);
+    write(TMRINITCNT, 0);
 }
 
 pub unsafe fn lapic_timer_elapsed() -> u32 {
-    let timer_current_cnt = TMRCURRCNT as *mut u32;
-    u32::MAX - ptr::read_volatile(timer_current_cnt)
+    u32::MAX - read(TMRCURRCNT)
 }
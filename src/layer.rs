@@ -0,0 +1,294 @@
+use crate::graphics::{Graphic, PixelBuffer, PixelWriter, Rect, Rgb, GRAPHIC};
+use crate::BG_COLOR;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static! {
+    pub static ref LAYER_MANAGER: Mutex<LayerManager> = Mutex::new(LayerManager::new());
+}
+
+// Off-screen pixel buffer. Pixels equal to the transparency key are not composited.
+pub struct Window {
+    buffer: PixelBuffer,
+    transparent: Option<Rgb>,
+    // area drawn since the last flush, in window coordinates
+    dirty: Rect,
+}
+
+impl Window {
+    pub fn new(width: usize, height: usize, fill: Rgb) -> Self {
+        Window {
+            buffer: PixelBuffer::new(width, height, fill),
+            transparent: None,
+            dirty: Rect::new(0, 0, width as i32, height as i32),
+        }
+    }
+
+    pub fn set_transparent(&mut self, key: Option<Rgb>) {
+        self.transparent = key;
+        self.mark_dirty(self.bounds());
+    }
+
+    pub fn bounds(&self) -> Rect {
+        self.buffer.bounds()
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = self.dirty.union(&rect.intersection(&self.bounds()));
+    }
+
+    fn take_dirty(&mut self) -> Rect {
+        core::mem::replace(&mut self.dirty, Rect::new(0, 0, 0, 0))
+    }
+
+    // Move the rows [y + lines, y + height) up by `lines` and fill the rows left behind.
+    pub fn scroll_up(&mut self, y: usize, height: usize, lines: usize, fill: Rgb) {
+        let lines = lines.min(height);
+        let width = self.buffer.width() as i32;
+        let (y, height, lines) = (y as i32, height as i32, lines as i32);
+        self.buffer
+            .copy_rect(Rect::new(0, y + lines, width, height - lines), 0, y);
+        self.buffer
+            .fill(Rect::new(0, y + height - lines, width, lines), fill);
+        self.mark_dirty(Rect::new(0, y, width, height));
+    }
+}
+
+impl PixelWriter for Window {
+    fn width(&self) -> usize {
+        self.buffer.width()
+    }
+
+    fn height(&self) -> usize {
+        self.buffer.height()
+    }
+
+    fn write(&mut self, x: usize, y: usize, rgb: Rgb) {
+        self.buffer.write(x, y, rgb);
+        self.mark_dirty(Rect::new(x as i32, y as i32, 1, 1));
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: Rgb) {
+        let rect = Rect::new(x as i32, y as i32, width as i32, height as i32);
+        let changed = self.buffer.fill(rect, rgb);
+        self.mark_dirty(changed);
+    }
+}
+
+pub type LayerId = usize;
+
+struct Layer {
+    id: LayerId,
+    x: i32,
+    y: i32,
+    window: Window,
+}
+
+impl Layer {
+    fn rect(&self) -> Rect {
+        self.window.bounds().offset(self.x, self.y)
+    }
+}
+
+// Composites windows onto the framebuffer. Only areas that changed are redrawn:
+// what was drawn into a window since the last flush, and what was covered or
+// uncovered by moving, raising or hiding a layer.
+pub struct LayerManager {
+    layers: Vec<Layer>,
+    // visible layers, bottom first
+    stack: Vec<LayerId>,
+    next_id: LayerId,
+    // screen areas to recompose on the next flush
+    damaged: Vec<Rect>,
+    // background layer covering the whole screen
+    desktop: Option<LayerId>,
+}
+
+impl LayerManager {
+    pub fn new() -> Self {
+        LayerManager {
+            layers: Vec::new(),
+            stack: Vec::new(),
+            next_id: 0,
+            damaged: Vec::new(),
+            desktop: None,
+        }
+    }
+
+    // The new layer is hidden until it is given a place with `set_z`.
+    pub fn new_layer(&mut self, window: Window, x: i32, y: i32) -> LayerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.layers.push(Layer { id, x, y, window });
+        id
+    }
+
+    pub fn remove_layer(&mut self, id: LayerId) {
+        self.set_z(id, None);
+        self.layers.retain(|layer| layer.id != id);
+    }
+
+    fn layer(&self, id: LayerId) -> Option<&Layer> {
+        self.layers.iter().find(|layer| layer.id == id)
+    }
+
+    fn layer_mut(&mut self, id: LayerId) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|layer| layer.id == id)
+    }
+
+    pub fn window(&mut self, id: LayerId) -> Option<&mut Window> {
+        self.layer_mut(id).map(|layer| &mut layer.window)
+    }
+
+    // Replace the layer's window, e.g. to resize it.
+    pub fn set_window(&mut self, id: LayerId, window: Window) {
+        let visible = self.is_visible(id);
+        let layer = match self.layer_mut(id) {
+            Some(layer) => layer,
+            None => return,
+        };
+        let old = layer.rect();
+        layer.window = window;
+        let new = layer.rect();
+        if visible {
+            self.damaged.push(old);
+            self.damaged.push(new);
+        }
+    }
+
+    pub fn position(&self, id: LayerId) -> Option<(i32, i32)> {
+        self.layer(id).map(|layer| (layer.x, layer.y))
+    }
+
+    pub fn is_visible(&self, id: LayerId) -> bool {
+        self.stack.contains(&id)
+    }
+
+    pub fn move_to(&mut self, id: LayerId, x: i32, y: i32) {
+        let visible = self.is_visible(id);
+        let layer = match self.layer_mut(id) {
+            Some(layer) => layer,
+            None => return,
+        };
+        let old = layer.rect();
+        layer.x = x;
+        layer.y = y;
+        let new = layer.rect();
+        if visible {
+            self.damaged.push(old);
+            self.damaged.push(new);
+        }
+    }
+
+    pub fn move_relative(&mut self, id: LayerId, dx: i32, dy: i32) {
+        if let Some((x, y)) = self.position(id) {
+            self.move_to(id, x + dx, y + dy);
+        }
+    }
+
+    // Place the layer at height `z` (0 is the bottom, values past the top are clamped),
+    // or hide it with `None`.
+    pub fn set_z(&mut self, id: LayerId, z: Option<usize>) {
+        let rect = match self.layer(id) {
+            Some(layer) => layer.rect(),
+            None => return,
+        };
+        let was_visible = self.is_visible(id);
+        self.stack.retain(|&other| other != id);
+        if let Some(z) = z {
+            self.stack.insert(z.min(self.stack.len()), id);
+        }
+        if was_visible || z.is_some() {
+            self.damaged.push(rect);
+        }
+    }
+
+    pub fn raise_to_top(&mut self, id: LayerId) {
+        self.set_z(id, Some(usize::MAX));
+    }
+
+    // After GRAPHIC has been replaced with one of another size: resize the desktop
+    // and recompose the whole screen.
+    pub fn resize_screen(&mut self, width: usize, height: usize) {
+        if let Some(desktop) = self.desktop {
+            self.set_window(desktop, Window::new(width, height, BG_COLOR));
+        }
+        self.damaged
+            .push(Rect::new(0, 0, width as i32, height as i32));
+    }
+
+    // Recompose every changed area onto the framebuffer.
+    pub fn flush(&mut self) {
+        let graphic = match unsafe { GRAPHIC.as_mut() } {
+            Some(graphic) => graphic,
+            None => return,
+        };
+        let screen = Rect::new(0, 0, graphic.width() as i32, graphic.height() as i32);
+
+        let mut areas = core::mem::take(&mut self.damaged);
+        for layer in self.layers.iter_mut() {
+            let dirty = layer.window.take_dirty();
+            if !dirty.is_empty() && self.stack.contains(&layer.id) {
+                areas.push(dirty.offset(layer.x, layer.y));
+            }
+        }
+
+        // merge overlapping areas so that no pixel is composed twice
+        let mut merged: Vec<Rect> = Vec::new();
+        for area in areas {
+            let mut area = area.intersection(&screen);
+            if area.is_empty() {
+                continue;
+            }
+            while let Some(i) = merged
+                .iter()
+                .position(|other| !other.intersection(&area).is_empty())
+            {
+                area = area.union(&merged.swap_remove(i));
+            }
+            merged.push(area);
+        }
+
+        for area in merged {
+            self.compose(graphic, area);
+        }
+        graphic.flush();
+    }
+
+    // Draw the layers bottom to top into the shadow buffer. Nothing reaches the screen
+    // before `Graphic::flush`, so intermediate states are never visible.
+    fn compose(&self, graphic: &mut Graphic, area: Rect) {
+        graphic.fill(area, BG_COLOR);
+        for &id in self.stack.iter() {
+            let layer = match self.layer(id) {
+                Some(layer) => layer,
+                None => continue,
+            };
+            let overlap = layer.rect().intersection(&area);
+            if overlap.is_empty() {
+                continue;
+            }
+            graphic.blit(
+                overlap.x,
+                overlap.y,
+                &layer.window.buffer,
+                overlap.offset(-layer.x, -layer.y),
+                layer.window.transparent,
+            );
+        }
+    }
+}
+
+// Create the desktop background layer covering the whole screen.
+pub fn init() {
+    let (width, height) = match unsafe { GRAPHIC.as_ref() } {
+        Some(graphic) => (graphic.width(), graphic.height()),
+        None => return,
+    };
+    let mut layers = LAYER_MANAGER.lock();
+    let desktop = layers.new_layer(Window::new(width, height, BG_COLOR), 0, 0);
+    layers.set_z(desktop, Some(0));
+    layers.desktop = Some(desktop);
+    layers.flush();
+}
@@ -7,34 +7,48 @@
 
 mod acpi;
 mod allocator;
+mod ansi;
 mod ascii_font;
+mod backtrace;
+mod bochs;
+#[cfg(feature = "buddy-frame-manager")]
+mod buddy;
 mod console;
+mod font;
 mod frame;
 mod graphics;
+mod hpet;
 mod interrupts;
 mod ioapic;
+mod keyboard;
 mod lapic;
+mod layer;
+mod mouse;
 mod paging;
+mod panic_screen;
 mod pci;
+mod power;
+mod ps2;
+mod rtc;
 mod segment;
 mod serial;
+mod shell;
+mod smp;
+mod task;
+mod time;
+mod timer;
 
-use alloc::{boxed::Box, vec::Vec};
 use console::CONSOLE;
 use core::arch::asm;
 use core::fmt::Write;
 use core::panic::PanicInfo;
-use frame::{BitMapFrameManager, BITMAP_FRAME_MANAGER};
+use core::ptr::addr_of;
 use graphics::{Graphic, Rgb};
 use pci::list_pci_devices;
 use rusmikan::{FrameBufferConfig, MemoryMap};
-use x86_64::{
-    structures::paging::{OffsetPageTable, PageTable},
-    VirtAddr,
-};
+use x86_64::VirtAddr;
 
-use crate::{allocator::ALLOCATOR, paging::active_level_4_table};
-use x86_64::structures::paging::Translate;
+use crate::allocator::ALLOCATOR;
 
 extern crate alloc;
 
@@ -68,17 +82,12 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("{}", info);
-    exit_qemu(QemuExitCode::Failed);
-    loop {
-        unsafe {
-            asm!("hlt");
-        }
-    }
+    panic_screen::panic(info)
 }
 
+// Page aligned so that the lowest page can be unmapped as a guard page.
 #[derive(Debug)]
-#[repr(align(16))]
+#[repr(align(4096))]
 struct KernelMainStack([u8; 1024 * 1024]);
 
 #[no_mangle]
@@ -91,67 +100,43 @@ pub extern "sysv64" fn kernel_main_new_stack(
     rsdp: u64,
 ) -> ! {
     serial_println!("System Info");
+    if !backtrace::has_symbols() {
+        serial_println!("symbols not embedded, backtraces will show addresses only");
+    }
     unsafe { segment::init() };
-    unsafe { BitMapFrameManager::init(memory_map) };
+    unsafe { frame::init(memory_map) };
     unsafe { paging::init() };
+    // an overflow of the boot stack now faults instead of overwriting other statics
+    unsafe { paging::unmap_guard_page(VirtAddr::from_ptr(addr_of!(KERNEL_MAIN_STACK))) };
 
-    let graphic = unsafe { Graphic::init(*fb_config) };
-    graphic.clear();
+    unsafe { Graphic::init(*fb_config) };
+    layer::init();
+    console::relayout();
 
     unsafe { acpi::init_rsdp(rsdp) };
+    unsafe { hpet::init() };
+    unsafe { time::init() };
+    task::init();
     unsafe { interrupts::init() };
-
-    println!("This is Rusmikan");
-    println!("1 + 2 = {}", 1 + 2);
-    // x86_64::instructions::interrupts::int3();
-
-    list_pci_devices();
+    unsafe { smp::init(memory_map) };
 
     let mm = memory_map.descriptors();
     for d in mm {
         serial_println!("{:x?}", d);
     }
-    let phys_mem_offset = VirtAddr::new(0);
-    let l4_table = unsafe { active_level_4_table(phys_mem_offset) };
-    let mapper = unsafe { OffsetPageTable::new(l4_table, phys_mem_offset) };
-
-    //    for (i,entry) in l4_table.iter().enumerate() {
-    //        if !entry.is_unused() {
-    //            serial_println!("L4 Entry {}: {:?}", i, entry);
-    //
-    //            let phys = entry.frame().unwrap().start_address();
-    //            let virt = phys.as_u64() + phys_mem_offset.as_u64();
-    //            let ptr = VirtAddr::new(virt).as_mut_ptr();
-    //            let l3_table: &PageTable = unsafe { &*ptr };
-    //       }
-    //    }
-
-    let addresses = [0x0, 0xb8000, 0x201008];
-
-    for &address in &addresses {
-        let virt = VirtAddr::new(address);
-        let phys = mapper.translate_addr(virt);
-        serial_println!("{:?} -> {:?}", virt, phys);
-    }
+    // the memory map and everything else the loader passed are not used after this
+    unsafe { frame::reclaim_boot_memory(memory_map) };
 
-    {
-        let mut vec: Vec<u32> = Vec::new();
-        vec.push(1);
-        vec.push(2);
-    }
+    log!("This is Rusmikan");
+    println!("1 + 2 = {}", 1 + 2);
+    log!("{} CPUs online", smp::online_cpus());
+    // x86_64::instructions::interrupts::int3();
 
-    {
-        let x = Box::new([0u8; 1024]);
-        let y = Box::new([0u8; 4096]);
-        let z = Box::new([0u8; 1024]);
-        serial_println!("{:p}", x);
-        serial_println!("{:p}", y);
-        serial_println!("{:p}", z);
-    }
+    list_pci_devices();
+    unsafe { bochs::init() };
 
-    //{
-    //    let x = Box::new([0u8; 8192]);
-    //}
+    task::spawn(shell::run);
+    task::spawn(mouse::run);
 
     // panic!();
     loop {
@@ -172,7 +157,15 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// println with the time since boot in front, for boot and driver messages
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::println!("[{}] {}", $crate::time::Instant::now(), format_args!($($arg)*)));
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
+    // the same bytes go to the serial port, where the terminal interprets the escapes
+    serial::_print(args);
     CONSOLE.lock().write_fmt(args).unwrap();
 }
@@ -0,0 +1,219 @@
+use crate::graphics::{PixelWriter, Rgb, GRAPHIC};
+use crate::layer::{Window, LAYER_MANAGER};
+use crate::{ps2, task};
+use core::sync::atomic::{AtomicI32, AtomicU8, AtomicUsize, Ordering};
+
+// mouse commands
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_GET_ID: u8 = 0xf2;
+
+// device ID reported after the IntelliMouse "knock" (sample rates 200, 100, 80)
+const INTELLIMOUSE_ID: u8 = 3;
+
+// first byte of a packet
+const PACKET_ALWAYS_ONE: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const PACKET_X_OVERFLOW: u8 = 0x40;
+const PACKET_Y_OVERFLOW: u8 = 0x80;
+const PACKET_BUTTONS: u8 = 0x07;
+
+pub const BUTTON_LEFT: u8 = 0x01;
+pub const BUTTON_RIGHT: u8 = 0x02;
+pub const BUTTON_MIDDLE: u8 = 0x04;
+
+const CURSOR_WIDTH: usize = 15;
+const CURSOR_HEIGHT: usize = 24;
+const CURSOR_SHAPE: [&[u8; CURSOR_WIDTH]; CURSOR_HEIGHT] = [
+    b"@              ",
+    b"@@             ",
+    b"@.@            ",
+    b"@..@           ",
+    b"@...@          ",
+    b"@....@         ",
+    b"@.....@        ",
+    b"@......@       ",
+    b"@.......@      ",
+    b"@........@     ",
+    b"@.........@    ",
+    b"@..........@   ",
+    b"@...........@  ",
+    b"@............@ ",
+    b"@......@@@@@@@@",
+    b"@......@       ",
+    b"@....@@.@      ",
+    b"@...@ @.@      ",
+    b"@..@   @.@     ",
+    b"@.@    @.@     ",
+    b"@@      @.@    ",
+    b"@       @.@    ",
+    b"         @.@   ",
+    b"         @@@   ",
+];
+const CURSOR_EDGE: Rgb = Rgb { r: 0, g: 0, b: 0 };
+const CURSOR_FILL: Rgb = Rgb {
+    r: 255,
+    g: 255,
+    b: 255,
+};
+// pixels of this color around the arrow are see-through
+const CURSOR_TRANSPARENT: Rgb = Rgb { r: 1, g: 2, b: 3 };
+
+// Packet assembly state, only touched by the interrupt handler.
+static PACKET: [AtomicU8; 4] = [
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+];
+static PACKET_INDEX: AtomicUsize = AtomicUsize::new(0);
+static PACKET_SIZE: AtomicUsize = AtomicUsize::new(3);
+
+// Decoded state, read by tasks.
+static X: AtomicI32 = AtomicI32::new(0);
+static Y: AtomicI32 = AtomicI32::new(0);
+static WHEEL: AtomicI32 = AtomicI32::new(0);
+static BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseState {
+    pub x: i32,
+    pub y: i32,
+    // accumulated wheel movement, positive is towards the user
+    pub wheel: i32,
+    pub buttons: u8,
+}
+
+pub fn state() -> MouseState {
+    MouseState {
+        x: X.load(Ordering::Relaxed),
+        y: Y.load(Ordering::Relaxed),
+        wheel: WHEEL.load(Ordering::Relaxed),
+        buttons: BUTTONS.load(Ordering::Relaxed),
+    }
+}
+
+// Enable the auxiliary port and the mouse behind it. Called with interrupts disabled.
+pub unsafe fn init() {
+    ps2::write_command(ps2::CMD_ENABLE_AUX);
+    let config = ps2::read_config();
+    ps2::write_config((config | ps2::CONFIG_AUX_INTERRUPT) & !ps2::CONFIG_AUX_CLOCK_DISABLED);
+
+    ps2::send_aux(MOUSE_SET_DEFAULTS);
+    for rate in [200, 100, 80] {
+        ps2::send_aux(MOUSE_SET_SAMPLE_RATE);
+        ps2::send_aux(rate);
+    }
+    if ps2::send_aux(MOUSE_GET_ID) && ps2::wait_data() == Some(INTELLIMOUSE_ID) {
+        // the 4th byte carries the wheel movement
+        PACKET_SIZE.store(4, Ordering::SeqCst);
+    }
+    ps2::send_aux(MOUSE_ENABLE_REPORTING);
+
+    if let Some(graphic) = GRAPHIC.as_ref() {
+        X.store(graphic.horizontal_resolution() as i32 / 2, Ordering::SeqCst);
+        Y.store(graphic.vertical_resolution() as i32 / 2, Ordering::SeqCst);
+    }
+}
+
+// Called from the IRQ12 handler with one byte of a packet.
+pub fn handle_interrupt() {
+    let byte = unsafe { ps2::read_data() };
+    let index = PACKET_INDEX.load(Ordering::Relaxed);
+
+    // resynchronize if the first byte doesn't look like one
+    if index == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+        return;
+    }
+    PACKET[index].store(byte, Ordering::Relaxed);
+    if index + 1 < PACKET_SIZE.load(Ordering::Relaxed) {
+        PACKET_INDEX.store(index + 1, Ordering::Relaxed);
+        return;
+    }
+    PACKET_INDEX.store(0, Ordering::Relaxed);
+
+    let flags = PACKET[0].load(Ordering::Relaxed);
+    BUTTONS.store(flags & PACKET_BUTTONS, Ordering::Relaxed);
+    if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+        return;
+    }
+
+    // 9-bit two's complement movement, y grows upwards
+    let mut dx = PACKET[1].load(Ordering::Relaxed) as i32;
+    if flags & PACKET_X_SIGN != 0 {
+        dx -= 0x100;
+    }
+    let mut dy = PACKET[2].load(Ordering::Relaxed) as i32;
+    if flags & PACKET_Y_SIGN != 0 {
+        dy -= 0x100;
+    }
+    if PACKET_SIZE.load(Ordering::Relaxed) == 4 {
+        // 4-bit two's complement
+        let wheel = ((PACKET[3].load(Ordering::Relaxed) << 4) as i8 >> 4) as i32;
+        WHEEL.fetch_add(wheel, Ordering::Relaxed);
+    }
+
+    let (width, height) = match unsafe { GRAPHIC.as_ref() } {
+        Some(graphic) => (
+            graphic.horizontal_resolution() as i32,
+            graphic.vertical_resolution() as i32,
+        ),
+        None => return,
+    };
+    let x = (X.load(Ordering::Relaxed) + dx).clamp(0, width - 1);
+    let y = (Y.load(Ordering::Relaxed) - dy).clamp(0, height - 1);
+    X.store(x, Ordering::Relaxed);
+    Y.store(y, Ordering::Relaxed);
+}
+
+// Keep the cursor on a screen resized to `width` x `height`.
+pub fn clamp_position(width: usize, height: usize) {
+    X.store(
+        X.load(Ordering::Relaxed).clamp(0, width as i32 - 1),
+        Ordering::Relaxed,
+    );
+    Y.store(
+        Y.load(Ordering::Relaxed).clamp(0, height as i32 - 1),
+        Ordering::Relaxed,
+    );
+}
+
+fn cursor_window() -> Window {
+    let mut window = Window::new(CURSOR_WIDTH, CURSOR_HEIGHT, CURSOR_TRANSPARENT);
+    window.set_transparent(Some(CURSOR_TRANSPARENT));
+    for (dy, line) in CURSOR_SHAPE.iter().enumerate() {
+        for (dx, &c) in line.iter().enumerate() {
+            match c {
+                b'@' => window.write(dx, dy, CURSOR_EDGE),
+                b'.' => window.write(dx, dy, CURSOR_FILL),
+                _ => {}
+            }
+        }
+    }
+    window
+}
+
+// Mouse cursor task: move the cursor layer to the position updated by the interrupt handler.
+pub fn run() {
+    let start = state();
+    let cursor = {
+        let mut layers = LAYER_MANAGER.lock();
+        let cursor = layers.new_layer(cursor_window(), start.x, start.y);
+        layers.raise_to_top(cursor);
+        layers.flush();
+        cursor
+    };
+    let mut drawn = (start.x, start.y);
+    loop {
+        let state = state();
+        if drawn != (state.x, state.y) {
+            let mut layers = LAYER_MANAGER.lock();
+            layers.move_to(cursor, state.x, state.y);
+            layers.flush();
+            drawn = (state.x, state.y);
+        }
+        task::sleep(10);
+    }
+}
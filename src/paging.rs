@@ -1,9 +1,13 @@
+use crate::frame::FRAME_MANAGER;
 use x86_64::addr::{PhysAddr, VirtAddr};
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::frame::PhysFrame;
-use x86_64::structures::paging::page::{Size1GiB, Size2MiB};
+use x86_64::structures::paging::mapper::{
+    MapToError, Mapper, OffsetPageTable, Translate, UnmapError,
+};
+use x86_64::structures::paging::page::{Page, Size1GiB, Size2MiB, Size4KiB};
 use x86_64::structures::paging::page_table::{PageTable, PageTableFlags};
-use x86_64::structures::paging::PageSize;
+use x86_64::structures::paging::{FrameAllocator, PageSize};
 
 static mut PML4_TABLE: PageTable = PageTable::new();
 static mut PDP_TABLE: PageTable = PageTable::new();
@@ -11,6 +15,18 @@ static mut PDP_TABLE: PageTable = PageTable::new();
 const EMPTY_PAGE_TABLE: PageTable = PageTable::new();
 static mut PAGE_DIRECTORY: [PageTable; 64] = [EMPTY_PAGE_TABLE; 64];
 
+// Physical memory is identity mapped, so page tables are reachable at offset 0.
+const PHYSICAL_MEMORY_OFFSET: u64 = 0;
+
+// End of the identity mapped physical range set up by `init`.
+pub const IDENTITY_MAP_END: u64 = 64 * Size1GiB::SIZE;
+
+// MMIO regions are mapped uncached into their own window above the identity map.
+// PML4 entry 256 = 0xffff_8000_0000_0000, 512 GiB
+const MMIO_BASE: u64 = 0xffff_8000_0000_0000;
+const MMIO_END: u64 = MMIO_BASE + 512 * Size1GiB::SIZE;
+static mut MMIO_NEXT: u64 = MMIO_BASE;
+
 pub unsafe fn init() {
     setup_identity_page_table();
     Cr3::write(get_phys_frame(&PML4_TABLE), Cr3Flags::empty());
@@ -56,3 +72,88 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
 
     &mut *page_table_ptr
 }
+
+unsafe fn mapper() -> OffsetPageTable<'static> {
+    OffsetPageTable::new(&mut PML4_TABLE, VirtAddr::new(PHYSICAL_MEMORY_OFFSET))
+}
+
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { mapper().translate_addr(addr) }
+}
+
+// Intermediate page tables are taken from FRAME_MANAGER.
+pub unsafe fn map_page<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    mapper()
+        .map_to_with_table_flags(page, frame, flags, parent_flags, &mut *FRAME_MANAGER.lock())?
+        .flush();
+    Ok(())
+}
+
+// The frame that was mapped is returned and is not freed.
+pub unsafe fn unmap_page<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let (frame, flush) = mapper().unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+// Unmap the 4 KiB page at `addr` in the identity map, so that touching it faults.
+// Used as a guard page at the bottom of stacks. The 2 MiB page holding it is split first,
+// with the page table taken from FRAME_MANAGER. The frame must never be handed out again.
+pub unsafe fn unmap_guard_page(addr: VirtAddr) {
+    let addr = addr.as_u64();
+    assert!(
+        addr < IDENTITY_MAP_END,
+        "guard page outside the identity map"
+    );
+    let directory = &mut PAGE_DIRECTORY[(addr / Size1GiB::SIZE) as usize];
+    let entry = &mut directory[((addr % Size1GiB::SIZE) / Size2MiB::SIZE) as usize];
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        let frame = FRAME_MANAGER
+            .lock()
+            .allocate_frame()
+            .expect("no frame to split a huge page");
+        let table = &mut *(frame.start_address().as_u64() as *mut PageTable);
+        let base = entry.addr();
+        let flags = entry.flags() & !PageTableFlags::HUGE_PAGE;
+        for (i, page) in table.iter_mut().enumerate() {
+            page.set_addr(base + i as u64 * Size4KiB::SIZE, flags);
+        }
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    unmap_page(Page::<Size4KiB>::containing_address(VirtAddr::new(addr)))
+        .expect("failed to unmap guard page");
+}
+
+// Map `size` bytes of MMIO starting at physical address `phys` and return the virtual address
+// corresponding to `phys`.
+pub unsafe fn map_mmio(phys: u64, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE;
+    let start = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+    let end = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys + size.max(1) - 1));
+    let num_pages = (end - start) + 1;
+    if MMIO_NEXT + num_pages * Size4KiB::SIZE > MMIO_END {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    let base = VirtAddr::new(MMIO_NEXT);
+    MMIO_NEXT += num_pages * Size4KiB::SIZE;
+    for (i, frame) in PhysFrame::range_inclusive(start, end).enumerate() {
+        let page = Page::<Size4KiB>::containing_address(base + i as u64 * Size4KiB::SIZE);
+        map_page(page, frame, flags)?;
+    }
+    Ok(base + (phys - start.start_address().as_u64()))
+}
@@ -0,0 +1,218 @@
+use crate::backtrace::{self, Backtrace};
+use crate::console::CONSOLE;
+use crate::font::{self, FONT};
+use crate::graphics::{Graphic, PixelWriter, Rgb, GRAPHIC};
+use crate::layer::LAYER_MANAGER;
+use crate::serial::SERIAL1;
+use crate::{exit_qemu, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::mem;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const PANIC_BG: Rgb = Rgb {
+    r: 0x80,
+    g: 0,
+    b: 0,
+};
+const PANIC_FG: Rgb = Rgb {
+    r: 0xff,
+    g: 0xff,
+    b: 0xff,
+};
+const MARGIN: usize = 8;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// CPU state at the point of failure. Only what can still be read afterwards:
+// general purpose registers are gone by the time a panic or exception handler runs.
+pub struct Registers {
+    // only known for exceptions
+    rip: Option<u64>,
+    rsp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rsp: u64;
+        unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+        Registers {
+            rip: None,
+            rsp,
+            rflags: rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+
+    // The interrupted code's registers, as saved by the CPU.
+    pub fn from_stack_frame(stack_frame: &InterruptStackFrame) -> Self {
+        Registers {
+            rip: Some(stack_frame.instruction_pointer.as_u64()),
+            rsp: stack_frame.stack_pointer.as_u64(),
+            rflags: stack_frame.cpu_flags,
+            ..Self::capture()
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(rip) = self.rip {
+            write!(f, "RIP {:#018x}  ", rip)?;
+        }
+        writeln!(f, "RSP {:#018x}  RFLAGS {:#010x}", self.rsp, self.rflags)?;
+        writeln!(f, "CR0 {:#018x}  CR2 {:#018x}", self.cr0, self.cr2)?;
+        writeln!(f, "CR3 {:#018x}  CR4 {:#018x}", self.cr3, self.cr4)
+    }
+}
+
+// Writes text straight into the framebuffer, wrapping at the right edge
+// and dropping whatever does not fit below.
+struct Screen<'a> {
+    graphic: &'a mut Graphic,
+    cell_width: usize,
+    cell_height: usize,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+}
+
+impl<'a> Screen<'a> {
+    fn new(graphic: &'a mut Graphic) -> Self {
+        let (cell_width, cell_height) = (FONT.width(), FONT.height());
+        let columns = graphic.width().saturating_sub(MARGIN * 2) / cell_width;
+        let rows = graphic.height().saturating_sub(MARGIN * 2) / cell_height;
+        let (width, height) = (graphic.width(), graphic.height());
+        graphic.fill_rect(0, 0, width, height, PANIC_BG);
+        Screen {
+            graphic,
+            cell_width,
+            cell_height,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+        }
+    }
+
+    fn flush(self) {
+        self.graphic.flush();
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        self.row += 1;
+    }
+}
+
+impl<'a> Write for Screen<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' {
+                self.newline();
+                continue;
+            }
+            let width = if font::is_wide(c) { 2 } else { 1 };
+            if self.column + width > self.columns {
+                self.newline();
+            }
+            if self.row >= self.rows {
+                break;
+            }
+            let x = MARGIN + self.column * self.cell_width;
+            let y = MARGIN + self.row * self.cell_height;
+            self.graphic.write_char(x, y, c, PANIC_FG);
+            self.column += width;
+        }
+        Ok(())
+    }
+}
+
+// Take the screen away from the console and the layers for good. Whoever held
+// their locks will never run again, and nobody else may draw over the report.
+unsafe fn take_screen() -> Option<&'static mut Graphic> {
+    CONSOLE.force_unlock();
+    mem::forget(CONSOLE.lock());
+    LAYER_MANAGER.force_unlock();
+    mem::forget(LAYER_MANAGER.lock());
+    GRAPHIC.as_mut()
+}
+
+fn halt() -> ! {
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+// Report an unrecoverable error on serial and a panic screen, then stop.
+// `report` writes the description and is called once for each output.
+pub fn die(
+    title: &str,
+    report: &dyn Fn(&mut dyn Write) -> fmt::Result,
+    registers: &Registers,
+    backtrace: &Backtrace,
+) -> ! {
+    x86_64::instructions::interrupts::disable();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // failed again while reporting, or on another CPU at the same time
+        halt();
+    }
+
+    // the serial port may be locked by the code that failed
+    unsafe { SERIAL1.force_unlock() };
+    serial_println!("{}", title);
+    let _ = report(&mut *SERIAL1.lock());
+    serial_println!("{}", registers);
+    serial_println!("Backtrace:\n{}", backtrace);
+
+    if let Some(graphic) = unsafe { take_screen() } {
+        let mut screen = Screen::new(graphic);
+        let _ = writeln!(screen, "{}", title);
+        let _ = writeln!(screen);
+        let _ = report(&mut screen);
+        let _ = writeln!(screen);
+        let _ = writeln!(screen, "{}", registers);
+        let _ = write!(screen, "Backtrace:\n{}", backtrace);
+        screen.flush();
+    }
+
+    // build with the `halt-on-panic` feature to keep the screen up under QEMU
+    if !cfg!(feature = "halt-on-panic") {
+        exit_qemu(QemuExitCode::Failed);
+    }
+    halt()
+}
+
+// Called from the panic handler. Inlined so that the backtrace starts at the panic machinery.
+#[inline(always)]
+pub fn panic(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    let backtrace = backtrace::capture();
+    die(
+        "KERNEL PANIC",
+        &|w| {
+            writeln!(w, "{}", info.message())?;
+            if let Some(location) = info.location() {
+                writeln!(w, "at {}", location)?;
+            }
+            Ok(())
+        },
+        &registers,
+        &backtrace,
+    )
+}
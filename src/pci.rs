@@ -91,6 +91,10 @@ impl PciDevices {
         }
     }
 
+    pub fn devices(&self) -> &[Device] {
+        &self.devices[..self.count]
+    }
+
     fn add_device(&mut self, device: Device) {
         if self.count > MAX_DEVICES {
             return;
@@ -126,7 +130,7 @@ impl Device {
         }
     }
 
-    fn read_header_type(self) -> u8 {
+    pub fn read_header_type(self) -> u8 {
         ((self.read(0x0c) >> 16) & 0xff) as u8
     }
 
@@ -138,11 +142,15 @@ impl Device {
         header_type & 0x80 == 0
     }
 
-    fn read_vendor_id(self) -> u16 {
+    pub fn read_vendor_id(self) -> u16 {
         (self.read(0x0) & 0xffff) as u16
     }
 
-    fn read_class_code(self) -> ClassCode {
+    pub fn read_device_id(self) -> u16 {
+        (self.read(0x0) >> 16) as u16
+    }
+
+    pub fn read_class_code(self) -> ClassCode {
         let r = self.read(0x08);
         ClassCode {
             base: ((r >> 24) & 0xff) as u8,
@@ -152,6 +160,21 @@ impl Device {
         }
     }
 
+    // Base address of BAR `index`, with the type bits masked off.
+    // A 64-bit memory BAR takes the next one as its upper half.
+    pub fn read_bar(self, index: u8) -> u64 {
+        let bar = self.read(0x10 + index * 4);
+        if bar.get_bit(0) {
+            // I/O space
+            return (bar & !0x3) as u64;
+        }
+        let mut addr = (bar & !0xf) as u64;
+        if bar.get_bits(1..3) == 0b10 && index < 5 {
+            addr |= (self.read(0x10 + (index + 1) * 4) as u64) << 32;
+        }
+        addr
+    }
+
     fn read_bus_numbers(self) -> u16 {
         (self.read(0x18) & 0xffff) as u16
     }
@@ -174,7 +197,15 @@ pub fn list_pci_devices() {
     }
 }
 
-fn scan_all_bus() -> PciDevices {
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<Device> {
+    scan_all_bus()
+        .devices()
+        .iter()
+        .copied()
+        .find(|dev| dev.read_vendor_id() == vendor_id && dev.read_device_id() == device_id)
+}
+
+pub fn scan_all_bus() -> PciDevices {
     let mut pci_devices = PciDevices::new();
     if Device::new(0, 0, 0).is_single_function_device() {
         pci_devices.scan_bus(0);
@@ -0,0 +1,118 @@
+use crate::acpi::{self, Fadt, SleepTypes};
+use crate::ps2::{write_command, CMD_PULSE_RESET};
+use crate::serial_println;
+use crate::time::{self, ClockSource, Duration, Instant};
+use crate::{exit_qemu, QemuExitCode};
+use core::arch::asm;
+use x86_64::instructions::{hlt, interrupts, tables::lidt};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+// PM1 Control Register
+// refs. https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#pm1-control-registers
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+// how long to wait for the hardware to act before trying the next way
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+// used instead when no clock source is running yet
+const SETTLE_SPINS: u64 = 100_000_000;
+
+fn settle() {
+    if time::clock_source() == ClockSource::None {
+        for _ in 0..SETTLE_SPINS {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    let start = Instant::now();
+    while start.elapsed() < SETTLE_TIME {
+        core::hint::spin_loop();
+    }
+}
+
+// Switch the chipset from legacy to ACPI mode, as the OSPM does before using fixed hardware.
+unsafe fn enable_acpi(fadt: &Fadt) {
+    let pm1a = match fadt.pm1_control_blocks().0 {
+        Some(pm1a) => pm1a,
+        None => return,
+    };
+    if pm1a.read_u16() & SCI_EN != 0 {
+        return;
+    }
+    if let Some((port, value)) = fadt.acpi_enable_command() {
+        acpi::Register::Io(port).write_u8(value);
+        let start = Instant::now();
+        while pm1a.read_u16() & SCI_EN == 0 && start.elapsed() < SETTLE_TIME {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Enter S5 (soft off): SLP_TYPx first, then SLP_EN, PM1a and PM1b alike.
+unsafe fn enter_s5(fadt: &Fadt, sleep_types: SleepTypes) {
+    enable_acpi(fadt);
+    let (pm1a, pm1b) = fadt.pm1_control_blocks();
+    let blocks = [(pm1a, sleep_types.a), (pm1b, sleep_types.b)];
+    for &(block, slp_typ) in blocks.iter() {
+        if let Some(register) = block {
+            let value = register.read_u16() & !(SLP_TYP_MASK | SLP_EN);
+            register.write_u16(value | (slp_typ as u16) << SLP_TYP_SHIFT);
+        }
+    }
+    for &(block, _) in blocks.iter() {
+        if let Some(register) = block {
+            register.write_u16(register.read_u16() | SLP_EN);
+        }
+    }
+}
+
+// Power off through ACPI. Falls back to the QEMU debug exit port, then halts.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    match (acpi::fadt(), acpi::s5_sleep_types()) {
+        (Some(fadt), Some(sleep_types)) => {
+            unsafe { enter_s5(fadt, sleep_types) };
+            settle();
+            serial_println!("ACPI shutdown failed");
+        }
+        _ => {
+            serial_println!("ACPI shutdown is not supported");
+        }
+    }
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        hlt();
+    }
+}
+
+// Restart through the FADT reset register, then the keyboard controller,
+// and finally a triple fault, which resets the CPU on any PC.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset_register()) {
+        unsafe { register.write_u8(value) };
+        settle();
+        serial_println!("ACPI reset failed");
+    }
+
+    // pulse the CPU reset line
+    unsafe { write_command(CMD_PULSE_RESET) };
+    settle();
+    serial_println!("keyboard controller reset failed");
+
+    // With an empty IDT the breakpoint can't be delivered, and neither can the
+    // double fault that follows.
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::new(0),
+        });
+        asm!("int3");
+    }
+    loop {
+        hlt();
+    }
+}
@@ -0,0 +1,88 @@
+use x86_64::instructions::port::Port;
+
+// 8042 PS/2 controller
+// refs. https://wiki.osdev.org/%228042%22_PS/2_Controller
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64; // read
+const COMMAND_PORT: u16 = 0x64; // write
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+
+pub const CMD_READ_CONFIG: u8 = 0x20;
+pub const CMD_WRITE_CONFIG: u8 = 0x60;
+pub const CMD_ENABLE_AUX: u8 = 0xa8;
+pub const CMD_WRITE_AUX: u8 = 0xd4;
+pub const CMD_PULSE_RESET: u8 = 0xfe;
+
+pub const CONFIG_KBD_INTERRUPT: u8 = 0x01;
+pub const CONFIG_AUX_INTERRUPT: u8 = 0x02;
+pub const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+pub const CONFIG_TRANSLATION: u8 = 0x40;
+
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+
+// give up polling after this many status reads
+const TIMEOUT: usize = 100_000;
+
+unsafe fn status() -> u8 {
+    Port::<u8>::new(STATUS_PORT).read()
+}
+
+pub unsafe fn read_data() -> u8 {
+    Port::<u8>::new(DATA_PORT).read()
+}
+
+// Wait for a byte from the controller or a device. Only usable while interrupts are off.
+pub unsafe fn wait_data() -> Option<u8> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Some(read_data());
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+unsafe fn wait_writable() {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+pub unsafe fn write_data(data: u8) {
+    wait_writable();
+    Port::<u8>::new(DATA_PORT).write(data);
+}
+
+pub unsafe fn write_command(command: u8) {
+    wait_writable();
+    Port::<u8>::new(COMMAND_PORT).write(command);
+}
+
+pub unsafe fn read_config() -> u8 {
+    write_command(CMD_READ_CONFIG);
+    wait_data().unwrap_or(0)
+}
+
+pub unsafe fn write_config(config: u8) {
+    write_command(CMD_WRITE_CONFIG);
+    write_data(config);
+}
+
+// Send a byte to the keyboard and wait for its ACK.
+pub unsafe fn send_keyboard(data: u8) -> bool {
+    write_data(data);
+    wait_data() == Some(ACK)
+}
+
+// Send a byte to the auxiliary (mouse) device and wait for its ACK.
+pub unsafe fn send_aux(data: u8) -> bool {
+    write_command(CMD_WRITE_AUX);
+    write_data(data);
+    wait_data() == Some(ACK)
+}
@@ -0,0 +1,188 @@
+use crate::acpi::rtc_century_register;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// MC146818 real-time clock in the CMOS RAM
+// refs. https://wiki.osdev.org/CMOS
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// bit 7 of the address port masks NMIs, leave it clear
+const CMOS_INDEX_MASK: u8 = 0x7f;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+// set in the hours register for PM in 12-hour mode
+const HOURS_PM: u8 = 0x80;
+
+// assumed when the FADT has no century register
+const DEFAULT_CENTURY: u16 = 20;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// A UTC calendar date and time. The RTC is assumed to run in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// Days from 1970-01-01 to the given civil date, proleptic Gregorian.
+// refs. http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00 UTC. Dates before that give 0.
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * SECONDS_PER_DAY as i64 + seconds).max(0) as u64
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+// ISO 8601
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+unsafe fn read_cmos(index: u8) -> u8 {
+    Port::<u8>::new(CMOS_ADDRESS).write(index & CMOS_INDEX_MASK);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+unsafe fn update_in_progress() -> bool {
+    read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+// Registers as stored, in the RTC's own format.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+unsafe fn read_raw(century_register: Option<u8>) -> RawTime {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_cmos(REG_SECONDS),
+        minute: read_cmos(REG_MINUTES),
+        hour: read_cmos(REG_HOURS),
+        day: read_cmos(REG_DAY),
+        month: read_cmos(REG_MONTH),
+        year: read_cmos(REG_YEAR),
+        century: century_register.map(|index| read_cmos(index)),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Read the current date and time.
+pub fn now() -> DateTime {
+    let century_register = rtc_century_register();
+    let (raw, status_b) = interrupts::without_interrupts(|| unsafe {
+        // An update may start right after the flag was checked, so read until
+        // two reads in a row agree.
+        let mut raw = read_raw(century_register);
+        loop {
+            let again = read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_cmos(REG_STATUS_B))
+    });
+
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+    // the PM flag is outside the BCD digits
+    let mut hour = convert(raw.hour & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is 0:00, 12 PM is 12:00
+        hour %= 12;
+        if raw.hour & HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = raw
+        .century
+        .map_or(DEFAULT_CENTURY, |century| convert(century) as u16);
+
+    DateTime {
+        year: century * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
@@ -1,19 +1,55 @@
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
 use x86_64::{
-    instructions::segmentation::*,
+    instructions::{segmentation::*, tables::load_tss},
     registers::segmentation::SegmentSelector,
-    structures::gdt::{Descriptor, GlobalDescriptorTable},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
 };
 
+// Interrupt Stack Table index used by the double fault handler.
+// A separate stack lets us report a double fault even if the kernel stack is exhausted.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+#[repr(align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
 static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 pub unsafe fn init() {
-    let code_selector = GDT.add_entry(Descriptor::kernel_code_segment());
-    let data_selector = GDT.add_entry(Descriptor::kernel_data_segment());
-    GDT.load();
+    // stack grows downwards, so IST entries point to the end
+    let stack_start = VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACK));
+    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        stack_start + IST_STACK_SIZE as u64;
+    load(&mut *addr_of_mut!(GDT), &*addr_of!(TSS));
+}
+
+// Application processors get their own GDT, TSS and IST stack since a busy TSS can't be shared.
+pub unsafe fn init_ap() {
+    let stack: &'static mut IstStack = Box::leak(Box::new(IstStack([0; IST_STACK_SIZE])));
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(TaskStateSegment::new()));
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack) + IST_STACK_SIZE as u64;
+    load(Box::leak(Box::new(GlobalDescriptorTable::new())), tss);
+}
+
+unsafe fn load(gdt: &'static mut GlobalDescriptorTable, tss: &'static TaskStateSegment) {
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    gdt.load();
     CS::set_reg(code_selector);
     SS::set_reg(data_selector);
     DS::set_reg(SegmentSelector::NULL);
     ES::set_reg(SegmentSelector::NULL);
     FS::set_reg(SegmentSelector::NULL);
     GS::set_reg(SegmentSelector::NULL);
+    load_tss(tss_selector);
 }
@@ -0,0 +1,273 @@
+use crate::acpi;
+use crate::bochs::{self, Mode};
+use crate::console::CONSOLE;
+use crate::frame::{FRAME_BYTES, FRAME_MANAGER};
+use crate::keyboard::{self, Layout};
+use crate::pci::scan_all_bus;
+use crate::power;
+use crate::time::{self, Instant, SystemTime};
+use crate::{print, println, task, JIFFIES};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::str;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+const PROMPT: &str = "> ";
+const MAX_HISTORY: usize = 32;
+struct Shell {
+    line: Vec<char>,
+    // index in `line` the next character is inserted at
+    cursor: usize,
+    history: VecDeque<String>,
+    // position in `history` while browsing it with the arrow keys
+    history_index: Option<usize>,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Shell {
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_index: None,
+        }
+    }
+
+    // 0 on a console narrower than the prompt, e.g. after `mode` switched to a small resolution
+    fn max_line_len() -> usize {
+        CONSOLE.lock().columns().saturating_sub(PROMPT.len() + 1)
+    }
+
+    // Redraw the prompt and the line on the current row. It goes through print! like any
+    // other output, so the serial terminal sees the same edits as the console.
+    fn redraw(&self, show_cursor: bool) {
+        let line: String = self.line.iter().collect();
+        // back to the start of the row, then erase what is left of a longer line
+        print!("\r{}{}\x1b[K", PROMPT, line);
+        if self.cursor < self.line.len() {
+            print!("\x1b[{}D", self.line.len() - self.cursor);
+        }
+        if show_cursor {
+            CONSOLE.lock().draw_cursor();
+        }
+    }
+
+    fn set_line(&mut self, line: &str) {
+        self.line = line.chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    fn handle_key(&mut self, key: DecodedKey) {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                self.cursor = self.line.len();
+                self.redraw(false);
+                println!();
+                let line: String = self.line.iter().collect();
+                self.line.clear();
+                self.cursor = 0;
+                self.history_index = None;
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == MAX_HISTORY {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                execute(&line);
+            }
+            // backspace
+            DecodedKey::Unicode('\u{8}') => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                }
+            }
+            // delete
+            DecodedKey::Unicode('\u{7f}') | DecodedKey::RawKey(KeyCode::Delete) => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
+                }
+            }
+            DecodedKey::Unicode(c) => {
+                if !c.is_control() && self.line.len() < Self::max_line_len() {
+                    self.line.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.cursor = self.cursor.saturating_sub(1),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                self.cursor = (self.cursor + 1).min(self.line.len())
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.cursor = self.line.len(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                let index = match self.history_index {
+                    Some(0) => return,
+                    Some(index) => index - 1,
+                    None if self.history.is_empty() => return,
+                    None => self.history.len() - 1,
+                };
+                self.history_index = Some(index);
+                let line = self.history[index].clone();
+                self.set_line(&line);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) => match self.history_index {
+                Some(index) if index + 1 < self.history.len() => {
+                    self.history_index = Some(index + 1);
+                    let line = self.history[index + 1].clone();
+                    self.set_line(&line);
+                }
+                Some(_) => {
+                    self.history_index = None;
+                    self.set_line("");
+                }
+                None => {}
+            },
+            DecodedKey::RawKey(_) => {}
+        }
+        self.redraw(true);
+    }
+}
+
+// Shell task: read keys queued by the keyboard interrupt and run commands.
+pub fn run() {
+    let mut shell = Shell::new();
+    shell.redraw(true);
+    loop {
+        match keyboard::read_key() {
+            // Shift+PgUp/PgDn page through the console scrollback
+            Some(input) if input.modifiers.shift && is_page_key(input.key) => {
+                let mut console = CONSOLE.lock();
+                let page = (console.rows() / 2) as isize;
+                match input.key {
+                    DecodedKey::RawKey(KeyCode::PageUp) => console.scroll_view(page),
+                    _ => console.scroll_view(-page),
+                }
+            }
+            Some(input) => shell.handle_key(input.key),
+            None => task::sleep(10),
+        }
+    }
+}
+
+fn is_page_key(key: DecodedKey) -> bool {
+    matches!(
+        key,
+        DecodedKey::RawKey(KeyCode::PageUp) | DecodedKey::RawKey(KeyCode::PageDown)
+    )
+}
+
+fn execute(line: &str) {
+    let mut args = line.split_whitespace();
+    let command = match args.next() {
+        Some(command) => command,
+        None => return,
+    };
+    match command {
+        "help" => {
+            println!("help     show this message");
+            println!("lspci    list PCI devices");
+            println!("meminfo  show physical memory usage");
+            println!("acpi     list ACPI tables");
+            println!("ticks    show timer interrupts since boot");
+            println!("uptime   show time since boot and the clock source");
+            println!("date     show the date and time (UTC)");
+            println!("time     run a command and show how long it took");
+            println!("clear    clear the screen");
+            println!("layout   select keyboard layout (us, jp)");
+            println!("mode     list or set the screen resolution (mode WxH)");
+            println!("reboot   restart the machine");
+            println!("shutdown power off the machine");
+        }
+        "lspci" => {
+            for dev in scan_all_bus().devices() {
+                println!(
+                    "{:02x}:{:02x}.{} vend {:04x} dev {:04x} class {}",
+                    dev.bus,
+                    dev.device,
+                    dev.function,
+                    dev.read_vendor_id(),
+                    dev.read_device_id(),
+                    dev.read_class_code()
+                );
+            }
+        }
+        "meminfo" => {
+            let (total, free) = {
+                let manager = FRAME_MANAGER.lock();
+                (manager.total_frames(), manager.free_frames())
+            };
+            let to_mib = |frames: usize| frames * FRAME_BYTES / 1024 / 1024;
+            println!("total: {} frames ({} MiB)", total, to_mib(total));
+            println!(
+                "used : {} frames ({} MiB)",
+                total - free,
+                to_mib(total - free)
+            );
+            println!("free : {} frames ({} MiB)", free, to_mib(free));
+        }
+        "acpi" => {
+            for table in acpi::tables() {
+                println!(
+                    "{} {:#010x} length {} revision {}",
+                    str::from_utf8(&table.signature).unwrap_or("????"),
+                    table.address,
+                    table.length,
+                    table.revision
+                );
+            }
+        }
+        "ticks" => println!("{}", unsafe { JIFFIES }),
+        "uptime" => {
+            println!("{} s", Instant::now());
+            print!("clock source {:?}", time::clock_source());
+            match time::tsc_frequency() {
+                Some(frequency) => println!(", TSC {} kHz", frequency / 1000),
+                None => println!(),
+            }
+        }
+        "date" => {
+            let now = SystemTime::now();
+            println!("{} ({})", now, now.unix_timestamp());
+        }
+        "time" => {
+            let command = line.trim_start()["time".len()..].trim_start();
+            let start = Instant::now();
+            execute(command);
+            println!("{:?}", start.elapsed());
+        }
+        "clear" => CONSOLE.lock().clear(),
+        "layout" => match args.next() {
+            Some("us") => keyboard::set_layout(Layout::Us),
+            Some("jp") => keyboard::set_layout(Layout::Jp),
+            _ => println!("usage: layout us|jp"),
+        },
+        "mode" => match args.next() {
+            None => {
+                let current = bochs::current_mode();
+                for mode in bochs::modes() {
+                    let mark = if Some(mode) == current { '*' } else { ' ' };
+                    println!("{} {}x{}", mark, mode.width, mode.height);
+                }
+            }
+            Some(arg) => match parse_mode(arg) {
+                Some(mode) => {
+                    if let Err(err) = bochs::set_mode(mode) {
+                        println!("mode: {:?}", err);
+                    }
+                }
+                None => println!("usage: mode [WxH]"),
+            },
+        },
+        "reboot" => power::reboot(),
+        "shutdown" => power::shutdown(),
+        _ => println!("{}: command not found", command),
+    }
+}
+
+fn parse_mode(arg: &str) -> Option<Mode> {
+    let (width, height) = arg.split_once('x')?;
+    Some(Mode {
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+    })
+}
@@ -0,0 +1,125 @@
+use crate::acpi::{madt, wait_milliseconds_with_pm_timer};
+use crate::frame::{self, FRAME_BYTES, FRAME_MANAGER};
+use crate::lapic::{lapic_id, send_ipi};
+use crate::paging::unmap_guard_page;
+use crate::{interrupts, segment, serial_println};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use rusmikan::MemoryMap;
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+
+const AP_STACK_FRAMES: usize = 16; // 64 KiB, plus an unmapped guard page below
+
+// ICR command
+const ICR_INIT: u32 = 0x00004500; // INIT, level assert
+const ICR_STARTUP: u32 = 0x00004600; // Startup IPI, vector in low byte
+
+extern "C" {
+    // absolute symbol, its address is the value
+    static ap_trampoline_base: u8;
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+    static ap_cr3: u64;
+    static ap_stack: u64;
+    static ap_entry: u64;
+}
+
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static BSP_LAPIC_ID: AtomicU32 = AtomicU32::new(0);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+// Physical address the AP trampoline (asm.s) is copied to.
+pub fn trampoline_addr() -> usize {
+    unsafe { &ap_trampoline_base as *const u8 as usize }
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+pub fn is_bsp() -> bool {
+    unsafe { lapic_id() == BSP_LAPIC_ID.load(Ordering::Relaxed) }
+}
+
+// Write `value` into the copy of the trampoline at the offset of `symbol`.
+unsafe fn set_trampoline_param(symbol: &u64, value: u64) {
+    let offset = symbol as *const u64 as usize - &ap_trampoline as *const u8 as usize;
+    ptr::write_volatile((trampoline_addr() + offset) as *mut u64, value);
+}
+
+// Boot every enabled processor listed in the MADT with INIT-SIPI-SIPI, one at a time.
+pub unsafe fn init(mm: &MemoryMap) {
+    let bsp = lapic_id();
+    BSP_LAPIC_ID.store(bsp, Ordering::SeqCst);
+
+    let start = &ap_trampoline as *const u8;
+    let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+    let trampoline = trampoline_addr();
+    assert!(size <= FRAME_BYTES, "AP trampoline is larger than a page");
+    // the page is kept from the frame manager, but the firmware may be using it
+    if !frame::is_free(mm, trampoline..trampoline + FRAME_BYTES) {
+        serial_println!(
+            "SMP: trampoline page {:#x} is not free memory, APs are not started",
+            trampoline
+        );
+        return;
+    }
+    ptr::copy_nonoverlapping(start, trampoline as *mut u8, size);
+    set_trampoline_param(&ap_cr3, Cr3::read().0.start_address().as_u64());
+    set_trampoline_param(&ap_entry, ap_main as usize as u64);
+
+    for processor in madt().processors.iter() {
+        if !processor.enabled || processor.apic_id as u32 == bsp {
+            continue;
+        }
+        let stack = match FRAME_MANAGER.lock().allocate(AP_STACK_FRAMES + 1) {
+            Some(frame) => {
+                unmap_guard_page(VirtAddr::new((frame * FRAME_BYTES) as u64));
+                (frame + 1 + AP_STACK_FRAMES) * FRAME_BYTES
+            }
+            None => break,
+        };
+        set_trampoline_param(&ap_stack, stack as u64);
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        let apic_id = processor.apic_id as u32;
+        send_ipi(apic_id, ICR_INIT);
+        wait_milliseconds_with_pm_timer(10);
+        for _ in 0..2 {
+            send_ipi(apic_id, ICR_STARTUP | (trampoline / FRAME_BYTES) as u32);
+            wait_milliseconds_with_pm_timer(1);
+        }
+
+        // give the AP up to 100 ms to reach ap_main
+        let mut waited = 0;
+        while !AP_STARTED.load(Ordering::SeqCst) && waited < 100 {
+            wait_milliseconds_with_pm_timer(1);
+            waited += 1;
+        }
+        if AP_STARTED.load(Ordering::SeqCst) {
+            serial_println!("CPU (APIC ID {}) is online", apic_id);
+        } else {
+            // Park it with INIT so that a slow AP doesn't come up later on the trampoline
+            // of the next one. Its stack is leaked all the same in case it is already
+            // running; the guard page can't be freed anyway, it is no longer mapped.
+            send_ipi(apic_id, ICR_INIT);
+            serial_println!("CPU (APIC ID {}) did not respond", apic_id);
+        }
+    }
+}
+
+// Entry point of application processors, called from the trampoline on their own stack.
+extern "sysv64" fn ap_main() -> ! {
+    unsafe {
+        segment::init_ap();
+        interrupts::init_ap();
+    }
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::enable();
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
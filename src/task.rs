@@ -0,0 +1,296 @@
+use crate::time::{Duration, Instant};
+use crate::timer;
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::mem;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const STACK_SIZE: usize = 64 * 1024;
+const IDLE_TASK_ID: u64 = u64::MAX;
+// how long a task runs before others waiting get their turn
+const TIME_SLICE: Duration = Duration::from_millis(10);
+
+extern "C" {
+    fn switch_context(current_rsp: *mut u64, next_rsp: u64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    // waiting for a timer to wake it
+    Sleeping,
+    Exited,
+}
+
+struct Task {
+    id: u64,
+    rsp: u64,
+    state: State,
+    // empty for the boot task, which runs on KERNEL_MAIN_STACK
+    stack: Vec<u8>,
+    entry: Option<Box<dyn FnOnce() + Send + 'static>>,
+}
+
+impl Task {
+    fn new(id: u64, entry: Box<dyn FnOnce() + Send + 'static>) -> Box<Self> {
+        let mut task = Box::new(Task {
+            id,
+            rsp: 0,
+            state: State::Ready,
+            stack: vec![0; STACK_SIZE],
+            entry: Some(entry),
+        });
+
+        // Initial stack as left behind by switch_context, so that it "returns" into task_start
+        // with the stack aligned as if task_start had been called.
+        //
+        // top - 8  : 0 (return address of task_start)
+        // top - 16 : task_start
+        // top - 24 : rbp, rbx, r12, r13, r14, r15 (0)
+        // top - 72 : rflags (interrupts disabled)
+        let top = (task.stack.as_ptr() as usize + STACK_SIZE) & !0xf;
+        let frame: [u64; 9] = [0x2, 0, 0, 0, 0, 0, 0, task_start as usize as u64, 0];
+        let rsp = top - mem::size_of_val(&frame);
+        unsafe { (rsp as *mut [u64; 9]).write(frame) };
+        task.rsp = rsp as u64;
+        task
+    }
+}
+
+// Round-robin scheduler. Tasks only run on the BSP, preempted by its LAPIC timer.
+// The timer is only armed for a time slice while other tasks are waiting to run,
+// so an idle CPU stays halted until a timer or device interrupt.
+//
+// `schedule` and `wake` run in interrupt context, possibly while the interrupted task holds
+// the allocator lock, so they must not allocate or free. Every task is in exactly one of the
+// queues below, and each has room for all tasks reserved in `spawn`, so none of them grows.
+// Exited tasks are freed by `reap` in task context.
+struct Scheduler {
+    current: Option<Box<Task>>,
+    idle: Option<Box<Task>>,
+    run_queue: VecDeque<Box<Task>>,
+    sleeping: Vec<Box<Task>>,
+    // exited tasks whose stack can't be freed until we have switched away from it
+    exited: Vec<Box<Task>>,
+    next_id: u64,
+    // tasks not yet reaped, except the idle task
+    num_tasks: usize,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler {
+            current: None,
+            idle: None,
+            run_queue: VecDeque::new(),
+            sleeping: Vec::new(),
+            exited: Vec::new(),
+            next_id: 1,
+            num_tasks: 0,
+        }
+    }
+
+    // Make room for every task in each queue. Called in task context only.
+    fn reserve(&mut self) {
+        let n = self.num_tasks;
+        self.run_queue
+            .reserve(n.saturating_sub(self.run_queue.len()));
+        self.sleeping.reserve(n.saturating_sub(self.sleeping.len()));
+        self.exited.reserve(n.saturating_sub(self.exited.len()));
+    }
+
+    // Others are waiting: make sure the current task is preempted at the end of its slice.
+    fn arm_slice_timer(&self) {
+        if !self.run_queue.is_empty() {
+            timer::start_slice(Instant::now() + TIME_SLICE);
+        }
+    }
+
+    fn wake(&mut self, id: u64) {
+        if let Some(i) = self.sleeping.iter().position(|task| task.id == id) {
+            let mut task = self.sleeping.swap_remove(i);
+            task.state = State::Ready;
+            self.run_queue.push_back(task);
+            self.arm_slice_timer();
+        }
+    }
+}
+
+// Timer callback of `sleep_for`.
+fn wake(id: u64) {
+    SCHEDULER.lock().wake(id);
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+// Turn the running boot code into task 0 and create the idle task.
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current = Some(Box::new(Task {
+            id: 0,
+            rsp: 0,
+            state: State::Running,
+            stack: Vec::new(),
+            entry: None,
+        }));
+        scheduler.num_tasks = 1;
+        scheduler.reserve();
+        timer::reserve(scheduler.num_tasks);
+        scheduler.idle = Some(Task::new(IDLE_TASK_ID, Box::new(idle)));
+    });
+}
+
+fn idle() {
+    loop {
+        reap();
+        interrupts::enable_and_hlt();
+    }
+}
+
+// Free the stacks of exited tasks, one at a time so the queue keeps its capacity.
+fn reap() {
+    loop {
+        let task = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let task = scheduler.exited.pop();
+            if task.is_some() {
+                scheduler.num_tasks -= 1;
+            }
+            task
+        });
+        match task {
+            // dropped with interrupts enabled, outside the scheduler lock
+            Some(task) => drop(task),
+            None => break,
+        }
+    }
+}
+
+// First code run by every new task, with interrupts still disabled by switch_context.
+extern "C" fn task_start() -> ! {
+    let entry = SCHEDULER
+        .lock()
+        .current
+        .as_mut()
+        .and_then(|task| task.entry.take());
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+pub fn spawn<F>(f: F) -> u64
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+    let mut task = Task::new(0, Box::new(f));
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        task.id = scheduler.next_id;
+        scheduler.next_id += 1;
+        scheduler.num_tasks += 1;
+        scheduler.reserve();
+        // sleep_for adds its timer with interrupts disabled, where the queue can't grow
+        timer::reserve(scheduler.num_tasks);
+        let id = task.id;
+        scheduler.run_queue.push_back(task);
+        scheduler.arm_slice_timer();
+        id
+    })
+}
+
+// Switch to the next runnable task. Must be called with interrupts disabled.
+pub fn schedule() {
+    let (current_rsp, next_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_none() {
+            return;
+        }
+
+        let current_runnable = scheduler.current.as_ref().unwrap().state == State::Running;
+        let mut next = match scheduler.run_queue.pop_front() {
+            Some(task) => task,
+            None if current_runnable => return,
+            None => match scheduler.idle.take() {
+                Some(idle) => idle,
+                // the idle task is the current one
+                None => return,
+            },
+        };
+
+        let mut current = scheduler.current.take().unwrap();
+        let current_rsp = &mut current.rsp as *mut u64;
+        if current.id == IDLE_TASK_ID {
+            current.state = State::Ready;
+            scheduler.idle = Some(current);
+        } else {
+            match current.state {
+                State::Running | State::Ready => {
+                    current.state = State::Ready;
+                    scheduler.run_queue.push_back(current);
+                }
+                State::Sleeping => scheduler.sleeping.push(current),
+                State::Exited => scheduler.exited.push(current),
+            }
+        }
+
+        next.state = State::Running;
+        let next_rsp = next.rsp;
+        scheduler.current = Some(next);
+        // a fresh slice for the next task
+        timer::stop_slice();
+        scheduler.arm_slice_timer();
+        (current_rsp, next_rsp)
+    };
+    // tasks live in Boxes, so current_rsp stays valid after the lock is released
+    unsafe { switch_context(current_rsp, next_rsp) };
+}
+
+fn set_current_state(state: State) {
+    if let Some(task) = SCHEDULER.lock().current.as_mut() {
+        task.state = state;
+    }
+}
+
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+pub fn sleep(msec: u64) {
+    sleep_for(Duration::from_millis(msec));
+}
+
+pub fn sleep_for(duration: Duration) {
+    let until = Instant::now() + duration;
+    interrupts::without_interrupts(|| {
+        let id = match SCHEDULER.lock().current.as_ref() {
+            Some(task) => task.id,
+            None => return,
+        };
+        // interrupts stay disabled until we have switched away, so the task is
+        // among the sleepers by the time the timer can fire
+        if timer::add_timer(until, wake, id).is_some() {
+            set_current_state(State::Sleeping);
+            schedule();
+        }
+    });
+    // without a free timer, give way to others until the time has come
+    while Instant::now() < until {
+        yield_now();
+    }
+}
+
+pub fn exit() -> ! {
+    interrupts::disable();
+    set_current_state(State::Exited);
+    schedule();
+    unreachable!("exited task was scheduled again");
+}
+
+pub fn current_id() -> Option<u64> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().current.as_ref().map(|task| task.id))
+}
@@ -0,0 +1,300 @@
+use crate::acpi::wait_milliseconds_with_pm_timer;
+use crate::hpet;
+use crate::rtc::{self, DateTime};
+use crate::serial_println;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+pub use core::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const CALIBRATION_MSEC: u32 = 50;
+
+// CPUID leaves
+const CPUID_TSC_CRYSTAL: u32 = 0x15;
+const CPUID_FREQUENCY: u32 = 0x16;
+const CPUID_MAX_EXTENDED: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8; // EDX of 0x8000_0007
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    // time stands still at 0 until `init` has picked a clock
+    None,
+    Tsc,
+    Hpet,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tsc {
+    frequency: u64,
+    invariant: bool,
+    // TSC value at ktime 0
+    base: u64,
+    // nanoseconds per TSC tick as 32.32 fixed point
+    mult: u64,
+}
+
+static mut CLOCK: ClockSource = ClockSource::None;
+static mut TSC: Option<Tsc> = None;
+// UNIX time in nanoseconds at ktime 0, from the RTC
+static mut BOOT_TIME_NS: u64 = 0;
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn max_leaf(leaf: u32) -> u32 {
+    unsafe { __cpuid(leaf & CPUID_MAX_EXTENDED).eax }
+}
+
+// The TSC has a constant rate in all P-, C- and T-states.
+fn is_tsc_invariant() -> bool {
+    max_leaf(CPUID_POWER_MANAGEMENT) >= CPUID_POWER_MANAGEMENT
+        && unsafe { __cpuid(CPUID_POWER_MANAGEMENT).edx } & INVARIANT_TSC != 0
+}
+
+// TSC frequency reported by the CPU: crystal clock times the TSC/crystal ratio,
+// or else the nominal base frequency.
+fn tsc_frequency_from_cpuid() -> Option<u64> {
+    let max = max_leaf(0);
+    if max >= CPUID_TSC_CRYSTAL {
+        let leaf = unsafe { __cpuid(CPUID_TSC_CRYSTAL) };
+        let (denominator, numerator, crystal_hz) = (leaf.eax, leaf.ebx, leaf.ecx);
+        if denominator != 0 && numerator != 0 && crystal_hz != 0 {
+            return Some(crystal_hz as u64 * numerator as u64 / denominator as u64);
+        }
+    }
+    if max >= CPUID_FREQUENCY {
+        let base_mhz = unsafe { __cpuid(CPUID_FREQUENCY).eax } & 0xffff;
+        if base_mhz != 0 {
+            return Some(base_mhz as u64 * 1_000_000);
+        }
+    }
+    None
+}
+
+unsafe fn calibrate_tsc_with_pm_timer() -> u64 {
+    let start = rdtsc();
+    wait_milliseconds_with_pm_timer(CALIBRATION_MSEC);
+    let end = rdtsc();
+    (end - start) * 1000 / CALIBRATION_MSEC as u64
+}
+
+// Find the TSC frequency and pick the clock source. Needs the ACPI tables and the HPET.
+// An invariant TSC is preferred, then the HPET; a TSC that may change its rate is the last resort.
+pub unsafe fn init() {
+    let invariant = is_tsc_invariant();
+    let frequency = tsc_frequency_from_cpuid().unwrap_or_else(|| calibrate_tsc_with_pm_timer());
+    if frequency != 0 {
+        // continue from the time counted so far
+        let now = ktime_ns();
+        let mult = ((NANOS_PER_SEC as u128) << 32) / frequency as u128;
+        let base = rdtsc().wrapping_sub((((now as u128) << 32) / mult) as u64);
+        TSC = Some(Tsc {
+            frequency,
+            invariant,
+            base,
+            mult: mult as u64,
+        });
+    }
+
+    CLOCK = match TSC {
+        Some(tsc) if tsc.invariant => ClockSource::Tsc,
+        _ if hpet::is_available() => ClockSource::Hpet,
+        Some(_) => ClockSource::Tsc,
+        None => ClockSource::None,
+    };
+    serial_println!(
+        "TSC: {} kHz, {}invariant, clock source {:?}",
+        frequency / 1000,
+        if invariant { "" } else { "not " },
+        CLOCK
+    );
+
+    // The RTC only counts seconds, so wall-clock time may be up to a second behind.
+    let date = rtc::now();
+    BOOT_TIME_NS = (date.to_unix() * NANOS_PER_SEC).saturating_sub(ktime_ns());
+    serial_println!("RTC: {}", date);
+}
+
+pub fn clock_source() -> ClockSource {
+    unsafe { CLOCK }
+}
+
+pub fn tsc_frequency() -> Option<u64> {
+    unsafe { TSC.map(|tsc| tsc.frequency) }
+}
+
+// Monotonic nanoseconds since boot.
+pub fn ktime_ns() -> u64 {
+    unsafe {
+        match (CLOCK, TSC) {
+            (ClockSource::Tsc, Some(tsc)) => {
+                let ticks = rdtsc().wrapping_sub(tsc.base);
+                ((ticks as u128 * tsc.mult as u128) >> 32) as u64
+            }
+            (ClockSource::Hpet, _) => hpet::nanoseconds().unwrap_or(0),
+            _ => 0,
+        }
+    }
+}
+
+// The TSC value at which `ktime_ns` reaches `ns`, if the TSC is the clock source.
+pub fn ns_to_tsc(ns: u64) -> Option<u64> {
+    unsafe {
+        match (CLOCK, TSC) {
+            (ClockSource::Tsc, Some(tsc)) => Some(
+                tsc.base
+                    .wrapping_add((((ns as u128) << 32) / tsc.mult as u128) as u64),
+            ),
+            _ => None,
+        }
+    }
+}
+
+// A point on the monotonic clock, for measuring how long something takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(ktime_ns())
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    // Zero if `earlier` is in fact later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// seconds.microseconds since boot, as in kernel log timestamps
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:5}.{:06}",
+            self.0 / NANOS_PER_SEC,
+            self.0 % NANOS_PER_SEC / 1000
+        )
+    }
+}
+
+// Wall-clock time, kept by the monotonic clock from the RTC reading at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+// `earlier` was in fact later, by the contained duration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemTimeError(pub Duration);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+impl SystemTime {
+    pub fn now() -> Self {
+        SystemTime(unsafe { BOOT_TIME_NS } + ktime_ns())
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        SystemTime(seconds.saturating_mul(NANOS_PER_SEC))
+    }
+
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        match self.0.checked_sub(earlier.0) {
+            Some(nanos) => Ok(Duration::from_nanos(nanos)),
+            None => Err(SystemTimeError(Duration::from_nanos(earlier.0 - self.0))),
+        }
+    }
+
+    // Seconds since 1970-01-01 00:00:00 UTC, as stored in file metadata.
+    pub fn unix_timestamp(&self) -> u64 {
+        self.0 / NANOS_PER_SEC
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix(self.unix_timestamp())
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        SystemTime(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        SystemTime(self.0 - duration.as_nanos() as u64)
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.date_time().fmt(f)
+    }
+}
@@ -0,0 +1,257 @@
+use crate::lapic::set_timer_deadline;
+use crate::time::{self, Instant};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Room for this many pending timers when the queue first grows.
+const INITIAL_TIMERS: usize = 16;
+
+// Called from the timer interrupt with the argument given to `add_timer`.
+// Callbacks are plain functions so that firing a timer frees nothing.
+pub type Callback = fn(u64);
+
+// A pending timer, for `cancel`. The generation is bumped each time the slot is released,
+// so a handle kept after its timer fired can't cancel a later timer in the same slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    slot: u32,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    callback: Callback,
+    arg: u64,
+}
+
+struct Slot {
+    generation: u32,
+    timer: Option<Timer>,
+    // position in `heap` while the timer is pending
+    heap_index: usize,
+}
+
+// Pending timers in a binary min-heap of (deadline in ktime_ns, slot), with the LAPIC timer
+// programmed for the earliest one only.
+// A deadline is armed on the Local APIC of the CPU that reprograms the queue, and
+// the timer interrupt of any CPU runs the expired callbacks.
+//
+// The timer interrupt must neither allocate nor free, as it may have interrupted a task
+// holding the allocator lock. Slots, the free list and the heap only grow in task context,
+// each with room for every slot, so the interrupt path just moves entries around.
+struct TimerQueue {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    heap: Vec<(u64, u32)>,
+    // End of the running task's time slice. It needs no callback: every timer
+    // interrupt ends in task::schedule, which switches tasks.
+    slice: Option<u64>,
+    // deadline the hardware is currently programmed for
+    armed: Option<u64>,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        TimerQueue {
+            slots: Vec::new(),
+            free: Vec::new(),
+            heap: Vec::new(),
+            slice: None,
+            armed: None,
+        }
+    }
+
+    // Make sure at least `additional` timers can be added. Allocates, so task context only.
+    fn reserve(&mut self, additional: usize) {
+        if self.free.len() >= additional {
+            return;
+        }
+        let len = self.slots.len() + additional - self.free.len();
+        while self.slots.len() < len {
+            self.free.push(self.slots.len() as u32);
+            self.slots.push(Slot {
+                generation: 0,
+                timer: None,
+                heap_index: 0,
+            });
+        }
+        self.free.reserve(len - self.free.len());
+        self.heap.reserve(len - self.heap.len());
+    }
+
+    fn insert(&mut self, deadline: u64, timer: Timer) -> Option<TimerHandle> {
+        let slot = self.free.pop()?;
+        self.slots[slot as usize].timer = Some(timer);
+        self.heap.push((deadline, slot));
+        self.sift_up(self.heap.len() - 1);
+        Some(TimerHandle {
+            slot,
+            generation: self.slots[slot as usize].generation,
+        })
+    }
+
+    fn cancel(&mut self, handle: TimerHandle) -> bool {
+        let index = match self.slots.get(handle.slot as usize) {
+            Some(slot) if slot.generation == handle.generation && slot.timer.is_some() => {
+                slot.heap_index
+            }
+            _ => return false,
+        };
+        self.remove(index);
+        true
+    }
+
+    fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+        match self.heap.first() {
+            Some(&(deadline, _)) if deadline <= now => Some(self.remove(0)),
+            _ => None,
+        }
+    }
+
+    // Take the timer at `index` in the heap and release its slot.
+    fn remove(&mut self, index: usize) -> Timer {
+        let last = self.heap.len() - 1;
+        self.swap(index, last);
+        let (_, slot) = self.heap.pop().unwrap();
+        if index < last {
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+        let slot_entry = &mut self.slots[slot as usize];
+        slot_entry.generation = slot_entry.generation.wrapping_add(1);
+        let timer = slot_entry.timer.take().unwrap();
+        self.free.push(slot);
+        timer
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.slots[self.heap[a].1 as usize].heap_index = a;
+        self.slots[self.heap[b].1 as usize].heap_index = b;
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        self.slots[self.heap[index].1 as usize].heap_index = index;
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.heap[parent].0 <= self.heap[index].0 {
+                break;
+            }
+            self.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut smallest = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.heap.len() && self.heap[child].0 < self.heap[smallest].0 {
+                    smallest = child;
+                }
+            }
+            if smallest == index {
+                break;
+            }
+            self.swap(index, smallest);
+            index = smallest;
+        }
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        let next = self.heap.first().map(|&(deadline, _)| deadline);
+        match (next, self.slice) {
+            (Some(next), Some(slice)) => Some(next.min(slice)),
+            (next, slice) => next.or(slice),
+        }
+    }
+
+    // Point the hardware at the earliest deadline, or stop it when nothing is pending.
+    fn reprogram(&mut self) {
+        let next = self.next_deadline();
+        if next != self.armed {
+            unsafe { set_timer_deadline(next) };
+            self.armed = next;
+        }
+    }
+}
+
+static TIMERS: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+
+// Make sure `additional` more timers can be added from interrupt handlers, where
+// `add_timer` can't grow the queue. Must not be called from an interrupt handler.
+pub fn reserve(additional: usize) {
+    interrupts::without_interrupts(|| TIMERS.lock().reserve(additional));
+}
+
+// Call `callback(arg)` from the timer interrupt once `deadline` has passed.
+// Called with interrupts enabled, the queue grows as needed. With interrupts disabled,
+// as in interrupt handlers, it may not allocate and returns None when no room is left.
+pub fn add_timer(deadline: Instant, callback: Callback, arg: u64) -> Option<TimerHandle> {
+    let may_grow = interrupts::are_enabled();
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if may_grow && timers.free.is_empty() {
+            let additional = timers.slots.len().max(INITIAL_TIMERS);
+            timers.reserve(additional);
+        }
+        let handle = timers.insert(deadline.as_nanos(), Timer { callback, arg })?;
+        timers.reprogram();
+        Some(handle)
+    })
+}
+
+// Withdraw a pending timer. Returns false if it has already fired or was cancelled before.
+pub fn cancel(handle: TimerHandle) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let cancelled = timers.cancel(handle);
+        if cancelled {
+            timers.reprogram();
+        }
+        cancelled
+    })
+}
+
+// Start a time slice ending at `deadline`, unless one is running already.
+pub fn start_slice(deadline: Instant) {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if timers.slice.is_none() {
+            timers.slice = Some(deadline.as_nanos());
+            timers.reprogram();
+        }
+    })
+}
+
+pub fn stop_slice() {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if timers.slice.take().is_some() {
+            timers.reprogram();
+        }
+    })
+}
+
+// Called from the timer interrupt: run every expired callback, then arm for the next one.
+// Callbacks run without the queue locked, so they may add or cancel timers.
+pub fn handle_interrupt() {
+    let now = time::ktime_ns();
+    {
+        let mut timers = TIMERS.lock();
+        // a one-shot timer is spent once it has fired
+        timers.armed = None;
+        if timers.slice.map_or(false, |slice| slice <= now) {
+            timers.slice = None;
+        }
+    }
+    loop {
+        let timer = TIMERS.lock().pop_expired(now);
+        match timer {
+            Some(timer) => (timer.callback)(timer.arg),
+            None => break,
+        }
+    }
+    TIMERS.lock().reprogram();
+}